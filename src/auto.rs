@@ -0,0 +1,234 @@
+use crate::{desync::Strategy, tls, Result};
+use bytes::BytesMut;
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+/// max domains with a cached strategy
+const CACHE_CAP: usize = 4096;
+/// ServerHello handshake type
+const SERVER_HELLO: u8 = 2;
+
+/// candidates after the global strategy, when none are given
+pub const CANDIDATES: &[&str] = &[
+    "direct",
+    "split:sni+1",
+    "split:1,split:sni+1,disorder",
+    "tlsrec:sni+1",
+    "tlsrec:sni+1,split:sni+1,disorder",
+    "split:sni+1,oob:sni+1",
+    "fake:ttl=3,split:sni+1,disorder",
+    "case:random,split:sni_mid,disorder",
+];
+
+/// Strategy discovery: candidates are tried in order until a ServerHello comes back,
+/// the winner is cached per domain and tried first, every use verifies it again.
+/// Entries not verified for the expiry time go first when the cache is full.
+/// With a store file the cache survives restarts.
+pub struct Auto {
+    pub candidates: Vec<Strategy>,
+    /// wait for the ServerHello
+    pub timeout: Duration,
+    expiry: Duration,
+    cache: Mutex<HashMap<String, Entry>>,
+    store: Option<PathBuf>,
+    /// changed since the last save
+    dirty: AtomicBool,
+}
+
+/// What is known about a domain
+struct Entry {
+    strategy: Strategy,
+    success: u32,
+    failure: u32,
+    /// the last time the strategy worked
+    verified: SystemTime,
+    /// failed after that, probe again
+    stale: bool,
+}
+
+impl Entry {
+    fn new(strategy: &Strategy) -> Self {
+        Self {
+            strategy: strategy.clone(),
+            success: 0,
+            failure: 0,
+            verified: UNIX_EPOCH,
+            stale: false,
+        }
+    }
+
+    fn fresh(&self, expiry: Duration) -> bool {
+        !self.stale && self.verified.elapsed().is_ok_and(|t| t < expiry)
+    }
+}
+
+impl Auto {
+    /// store - the cache file, loaded now if it exists
+    pub fn new(candidates: Vec<Strategy>, timeout: Duration, expiry: Duration, store: Option<PathBuf>) -> Self {
+        let cache = match &store {
+            Some(path) if path.exists() => load(path).unwrap_or_else(|e| {
+                log::error!("[auto] load {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            _ => HashMap::new(),
+        };
+        if let Some(path) = &store {
+            log::info!("[auto] {} domains from {}", cache.len(), path.display());
+        }
+        Self {
+            candidates,
+            timeout,
+            expiry,
+            cache: Mutex::new(cache),
+            store,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// the cached strategy, also past the expiry: the probe tells whether it still works
+    pub fn get(&self, domain: &str) -> Option<Strategy> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(domain)
+            .filter(|e| !e.stale)
+            .map(|e| e.strategy.clone())
+    }
+
+    /// the strategy worked for the domain
+    pub fn remember(&self, domain: &str, strategy: &Strategy) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAP && !cache.contains_key(domain) {
+            cache.retain(|_, e| e.fresh(self.expiry));
+            if cache.len() >= CACHE_CAP {
+                cache.clear();
+            }
+        }
+        let spec = strategy.to_string();
+        let entry = cache
+            .entry(domain.to_string())
+            .and_modify(|e| {
+                // a new winner starts over
+                if e.strategy.to_string() != spec {
+                    *e = Entry::new(strategy);
+                }
+            })
+            .or_insert_with(|| Entry::new(strategy));
+        entry.success += 1;
+        entry.verified = SystemTime::now();
+        entry.stale = false;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// the cached strategy stopped working, the domain is probed again next time
+    pub fn failed(&self, domain: &str) {
+        if let Some(e) = self.cache.lock().unwrap().get_mut(domain) {
+            e.failure += 1;
+            e.stale = true;
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// write the store file if anything changed
+    pub fn save(&self) {
+        let Some(path) = &self.store else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let text = {
+            let cache = self.cache.lock().unwrap();
+            let mut domains: Vec<&String> = cache.keys().collect();
+            domains.sort();
+            let mut text = String::from("# domain\tsuccess\tfailure\tverified (unix time)\tstale\tstrategy\n");
+            for d in domains {
+                let e = &cache[d];
+                let verified = e.verified.duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs());
+                let stale = e.stale as u8;
+                text += &format!("{}\t{}\t{}\t{}\t{}\t{}\n", d, e.success, e.failure, verified, stale, e.strategy);
+            }
+            text
+        };
+        // a whole file or the old one
+        let tmp = path.with_extension("tmp");
+        match std::fs::write(&tmp, text).and_then(|_| std::fs::rename(&tmp, path)) {
+            Ok(()) => log::debug!("[auto] saved {}", path.display()),
+            Err(e) => {
+                log::error!("[auto] save {}: {}", path.display(), e);
+                self.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<HashMap<String, Entry>> {
+    let mut cache = HashMap::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let f: Vec<&str> = line.splitn(6, '\t').collect();
+        match parse_entry(&f) {
+            Some((domain, entry)) => {
+                cache.insert(domain, entry);
+            }
+            None => log::warn!("[auto] {}: bad line {}", path.display(), n + 1),
+        }
+    }
+    Ok(cache)
+}
+
+/// domain, success, failure, verified, stale (0/1), strategy
+fn parse_entry(f: &[&str]) -> Option<(String, Entry)> {
+    let [domain, success, failure, verified, stale, strategy] = f else {
+        return None;
+    };
+    let entry = Entry {
+        strategy: strategy.parse().ok()?,
+        success: success.parse().ok()?,
+        failure: failure.parse().ok()?,
+        verified: UNIX_EPOCH + Duration::from_secs(verified.parse().ok()?),
+        stale: *stale == "1",
+    };
+    Some((domain.to_string(), entry))
+}
+
+/// The first server bytes, for a TLS hello only a ServerHello (or HelloRetryRequest) counts.
+/// None on RST, alert, EOF or timeout
+pub async fn probe(server: &mut TcpStream, timeout: Duration, tls: bool) -> Option<BytesMut> {
+    let need = if tls { tls::RECORD_HEADER + 1 } else { 1 };
+    let mut buf = BytesMut::with_capacity(4096);
+    let read = async {
+        while buf.len() < need {
+            if server.read_buf(&mut buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    match tokio::time::timeout(timeout, read).await {
+        Ok(Ok(())) if !tls => Some(buf),
+        Ok(Ok(())) if buf[0] == tls::HANDSHAKE && buf[tls::RECORD_HEADER] == SERVER_HELLO => Some(buf),
+        Ok(Ok(())) => {
+            log::debug!("[probe] not a ServerHello: {:?}", &buf[..need]);
+            None
+        }
+        Ok(Err(e)) => {
+            log::debug!("[probe] {}", e);
+            None
+        }
+        Err(_) => {
+            log::debug!("[probe] timeout");
+            None
+        }
+    }
+}
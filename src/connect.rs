@@ -0,0 +1,93 @@
+use crate::sys;
+use std::{io, net::{IpAddr, SocketAddr}, time::Duration};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    task::JoinSet,
+};
+
+/// RFC 8305 "Connection Attempt Delay"
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// All addresses with families interleaved, IPv6 first (RFC 8305 4).
+/// The preferred address (the last one that worked) goes before the others.
+pub fn sort_addrs(ips: &[IpAddr], preferred: Option<IpAddr>, port: u16) -> Vec<SocketAddr> {
+    let mut v6 = ips.iter().filter(|ip| ip.is_ipv6() && Some(**ip) != preferred);
+    let mut v4 = ips.iter().filter(|ip| ip.is_ipv4() && Some(**ip) != preferred);
+    let mut r: Vec<IpAddr> = preferred.filter(|ip| ips.contains(ip)).into_iter().collect();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => r.extend(a.into_iter().chain(b)),
+        }
+    }
+    r.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()
+}
+
+/// Happy eyeballs: start the attempts in order, each next one after ATTEMPT_DELAY
+/// or as soon as the previous one fails, the first established connection wins.
+/// Every attempt is limited by timeout, source - bind to this (foreign) address,
+/// setup - socket options before connect.
+pub async fn happy_eyeballs<F>(
+    addrs: Vec<SocketAddr>,
+    source: Option<IpAddr>,
+    timeout: Duration,
+    setup: F,
+) -> io::Result<TcpStream>
+where
+    F: Fn(&TcpSocket) -> io::Result<()> + Clone + Send + 'static,
+{
+    let mut pending = addrs.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address to connect");
+    let mut next = pending.next();
+
+    loop {
+        if let Some(addr) = next.take() {
+            log::trace!("connect attempt: {}", addr);
+            let setup = setup.clone();
+            attempts.spawn(async move {
+                let r = tokio::time::timeout(timeout, connect(addr, source, setup))
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+                (addr, r)
+            });
+        }
+        if attempts.is_empty() {
+            return Err(last_err);
+        }
+
+        tokio::select! {
+            Some(res) = attempts.join_next() => match res {
+                Ok((addr, Ok(stream))) => {
+                    log::debug!("connected: {}", addr);
+                    // dropping the JoinSet aborts the other attempts
+                    return Ok(stream);
+                }
+                Ok((addr, Err(e))) => {
+                    log::debug!("connect {}: {}", addr, e);
+                    last_err = e;
+                    next = pending.next();
+                }
+                Err(e) => {
+                    last_err = io::Error::other(e);
+                    next = pending.next();
+                }
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                next = pending.next();
+            }
+        }
+    }
+}
+
+async fn connect<F>(addr: SocketAddr, source: Option<IpAddr>, setup: F) -> io::Result<TcpStream>
+where
+    F: Fn(&TcpSocket) -> io::Result<()>,
+{
+    let sock = match source {
+        Some(source) => sys::transparent_socket(addr, source)?,
+        None => sys::new_socket(addr)?,
+    };
+    setup(&sock)?;
+    sock.connect(addr).await
+}
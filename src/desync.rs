@@ -0,0 +1,544 @@
+use crate::{fake::Fake, mutate::Case, pos::{Layout, Pos}, rng::{Range, Rng}, sys, tls};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpSocket, TcpStream},
+};
+
+pub type StepFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// ttl of the disorder step without ttl=
+pub const DISORDER_TTL: u8 = 2;
+
+/// One evasion technique. connect runs on the upstream socket before connecting,
+/// prepare - for every hello before anything is sent, before/after - around every part written,
+/// done - after the hello, before the relay.
+pub trait DesyncStep: fmt::Debug + Send + Sync {
+    /// socket options of the connection
+    fn connect(&self, _s: &TcpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// mutate the hello, add split points
+    fn prepare(&self, _hello: &mut Hello) {}
+
+    /// true - the step has sent the part itself
+    fn before<'a>(&'a self, _s: &'a TcpStream, _part: Part<'a>) -> StepFuture<'a, bool> {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn after<'a>(&'a self, _s: &'a TcpStream, _part: Part<'a>) -> StepFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// undo what hurts the relay
+    fn done(&self, _s: &TcpStream) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The hello being prepared
+#[derive(Debug)]
+pub struct Hello {
+    pub buf: Vec<u8>,
+    pub layout: Layout,
+    /// as the client sent it, records joined
+    pub original: Vec<u8>,
+    /// the random values of this hello: rand() positions, sizes, delays
+    pub rng: Rng,
+    /// split points: position, the step that set it
+    cuts: Vec<(usize, usize)>,
+    step: usize,
+}
+
+impl Hello {
+    pub fn new(buf: Vec<u8>) -> Self {
+        Self {
+            layout: Layout::new(&buf),
+            original: buf.clone(),
+            buf,
+            rng: Rng::new(),
+            cuts: Vec::new(),
+            step: 0,
+        }
+    }
+
+    pub fn eval(&self, pos: &Pos) -> Option<usize> {
+        pos.eval(&self.layout, &self.rng)
+    }
+
+    /// split point owned by the current step, see Part::marked
+    pub fn cut(&mut self, pos: usize) {
+        self.cuts.push((pos, self.step));
+    }
+
+    /// n bytes were inserted at pos, the points after it move
+    pub fn moved(&mut self, pos: usize, n: usize) {
+        self.layout.insert(pos, n);
+        for c in self.cuts.iter_mut().filter(|c| c.0 > pos) {
+            c.0 += n;
+        }
+    }
+
+    /// a new TLS record starts at pos
+    pub fn split_record(&mut self, pos: usize) -> bool {
+        if !tls::split_record(&mut self.buf, pos) {
+            return false;
+        }
+        self.moved(pos, tls::RECORD_HEADER);
+        true
+    }
+}
+
+/// A part of the hello about to be written
+#[derive(Debug, Clone, Copy)]
+pub struct Part<'a> {
+    pub n: usize,
+    pub data: &'a [u8],
+    /// from the hello start
+    pub offset: usize,
+    pub last: bool,
+    /// the part ends at a point set by this step
+    pub marked: bool,
+    /// normal ttl of the connection
+    pub ttl: u32,
+    pub hello: &'a Hello,
+}
+
+/// Ordered steps, configured as one string: `split:sni+1,disorder,fake:ttl=3,tlsrec:sni`,
+/// no steps - `direct`
+#[derive(Clone, Default)]
+pub struct Strategy {
+    spec: String,
+    steps: Vec<Arc<dyn DesyncStep>>,
+}
+
+impl Strategy {
+    /// socket options for the upstream socket, before connect
+    pub fn connect(&self, s: &TcpSocket) -> io::Result<()> {
+        self.steps.iter().try_for_each(|step| step.connect(s))
+    }
+
+    /// the hello is sent (or not needed), the relay starts
+    pub fn done(&self, s: &TcpStream) -> io::Result<()> {
+        self.steps.iter().try_for_each(|step| step.done(s))
+    }
+
+    /// prepare the hello, then write it part by part
+    pub async fn send(&self, s: &mut TcpStream, buf: Vec<u8>) -> io::Result<()> {
+        let mut hello = Hello::new(buf);
+        for (i, step) in self.steps.iter().enumerate() {
+            hello.step = i;
+            step.prepare(&mut hello);
+        }
+        let len = hello.buf.len();
+        let mut ends: Vec<usize> = hello.cuts.iter().map(|c| c.0).filter(|c| *c > 0 && *c < len).collect();
+        ends.sort_unstable();
+        ends.dedup();
+        log::debug!("[parts] {:?}", ends);
+
+        let ttl = sys::ttl(s)?;
+        s.set_nodelay(true)?;
+        let mut start = 0;
+        for (n, end) in ends.iter().copied().chain([len]).enumerate() {
+            let mut part = Part {
+                n,
+                data: &hello.buf[start..end],
+                offset: start,
+                last: end == len,
+                marked: false,
+                ttl,
+                hello: &hello,
+            };
+            let mut sent = false;
+            for (i, step) in self.steps.iter().enumerate() {
+                part.marked = hello.cuts.contains(&(end, i));
+                if step.before(s, part).await? {
+                    sent = true;
+                    break;
+                }
+            }
+            if !sent {
+                s.write_all(part.data).await?;
+            }
+            for (i, step) in self.steps.iter().enumerate() {
+                part.marked = hello.cuts.contains(&(end, i));
+                step.after(s, part).await?;
+            }
+            start = end;
+        }
+        s.set_nodelay(false)?;
+        sys::set_ttl(s, ttl)?;
+        self.done(s)
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "direct" {
+            return Ok(Self::default());
+        }
+        let items: Vec<String> = split_top(s, ',').into_iter().filter(|i| !i.trim().is_empty()).collect();
+        // the name is edited in place: past a record split the host range covers a record header
+        let name = |i: &String| split_top(i.trim(), ':').swap_remove(0);
+        if let Some(rec) = items.iter().position(|i| name(i) == "tlsrec") {
+            if let Some(i) = items[rec..].iter().find(|i| matches!(name(i).as_str(), "case" | "esni" | "dot")) {
+                return Err(format!("{}: must come before tlsrec", i.trim()));
+            }
+        }
+        let steps = items.iter().map(|s| parse_step(s)).collect::<Result<_, _>>()?;
+        Ok(Self { spec: s.to_string(), steps })
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.steps.is_empty() {
+            true => f.write_str("direct"),
+            false => f.write_str(&self.spec),
+        }
+    }
+}
+
+impl fmt::Debug for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.steps).finish()
+    }
+}
+
+/// `name[:arg[:key=value]...]`
+fn parse_step(s: &str) -> Result<Arc<dyn DesyncStep>, String> {
+    let mut args = split_top(s.trim(), ':').into_iter();
+    let name = args.next().unwrap_or_default();
+    let mut a = Args::default();
+    for arg in args {
+        match arg.split_once('=') {
+            Some((k, v)) => a.named.push((k.to_string(), v.to_string())),
+            None => a.plain.push(arg.to_string()),
+        }
+    }
+    let step: Arc<dyn DesyncStep> = match name.as_str() {
+        "split" => Arc::new(Split(a.pos(0, "at")?.ok_or("split: position expected")?)),
+        "sizes" => Arc::new(Sizes {
+            from: a.pos(usize::MAX, "from")?,
+            sizes: match a.plain.iter().map(|s| s.parse()).collect::<Result<Vec<_>, _>>()? {
+                v if v.is_empty() => return Err("sizes: size expected".into()),
+                v => v,
+            },
+        }),
+        "disorder" => Arc::new(Disorder(a.num(0, "ttl")?.unwrap_or(DISORDER_TTL))),
+        "sleep" => Arc::new(Sleep {
+            delay: a.num(0, "ms")?.ok_or("sleep: ms expected")?,
+            part: a.num(1, "part")?,
+        }),
+        "ack" => Arc::new(Ack(Duration::from_millis(a.num(0, "timeout")?.unwrap_or(ACK_TIMEOUT)))),
+        "mss" => Arc::new(Mss(a.num(0, "size")?.ok_or("mss: size expected")?)),
+        "window" => Arc::new(Window(a.num(0, "size")?.ok_or("window: size expected")?)),
+        "esni" => Arc::new(CaseStep(Case::FirstLast)),
+        "case" => Arc::new(CaseStep(a.get(0, "mode").unwrap_or("random").parse()?)),
+        "dot" => Arc::new(Dot),
+        "tlsrec" => Arc::new(TlsRec {
+            at: a.pos(0, "at")?.ok_or("tlsrec: position expected")?,
+            tcp: a.flag("tcp"),
+        }),
+        "oob" => Arc::new(Oob {
+            at: a.pos(0, "at")?.unwrap_or("sni+1".parse()?),
+            byte: a.num(1, "byte")?.unwrap_or(b'a'),
+        }),
+        "fake" => Arc::new(Fake {
+            count: a.num(usize::MAX, "count")?.unwrap_or(1),
+            ttl: a.num(usize::MAX, "ttl")?.unwrap_or(3),
+            sni: a.get(usize::MAX, "sni").unwrap_or("www.google.com").to_string(),
+            payload: match a.get(usize::MAX, "file") {
+                Some(f) => Some(std::fs::read(f).map_err(|e| format!("fake file {}: {}", f, e))?),
+                None => None,
+            },
+        }),
+        _ => return Err(format!("unknown step: {}", name)),
+    };
+    Ok(step)
+}
+
+#[derive(Default)]
+struct Args {
+    plain: Vec<String>,
+    named: Vec<(String, String)>,
+}
+
+impl Args {
+    /// the named value or the plain one at i
+    fn get(&self, i: usize, key: &str) -> Option<&str> {
+        self.named
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .or_else(|| self.plain.get(i).map(|v| v.as_str()))
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.plain.iter().any(|v| v == key)
+    }
+
+    fn num<T: FromStr>(&self, i: usize, key: &str) -> Result<Option<T>, String> {
+        self.get(i, key)
+            .map(|v| v.parse().map_err(|_| format!("bad {}: {}", key, v)))
+            .transpose()
+    }
+
+    fn pos(&self, i: usize, key: &str) -> Result<Option<Pos>, String> {
+        self.get(i, key).map(Pos::from_str).transpose()
+    }
+}
+
+/// split on sep outside of parentheses: rand(1,8)
+fn split_top(s: &str, sep: char) -> Vec<String> {
+    let mut r = vec![String::new()];
+    let mut depth = 0;
+    for c in s.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if c == sep && depth == 0 => {
+                r.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        r.last_mut().unwrap().push(c);
+    }
+    r
+}
+
+/// split at the position
+#[derive(Debug)]
+struct Split(Pos);
+
+impl DesyncStep for Split {
+    fn prepare(&self, hello: &mut Hello) {
+        if let Some(p) = hello.eval(&self.0) {
+            hello.cut(p);
+        }
+    }
+}
+
+/// Parts of the sizes one after another from the position (the hello start without from=),
+/// every size drawn once per hello: `sizes:2:rand(1,3):from=sni`
+#[derive(Debug)]
+struct Sizes {
+    from: Option<Pos>,
+    sizes: Vec<Range>,
+}
+
+impl DesyncStep for Sizes {
+    fn prepare(&self, hello: &mut Hello) {
+        let start = match &self.from {
+            Some(pos) => hello.eval(pos),
+            None => Some(0),
+        };
+        let Some(mut p) = start else {
+            return;
+        };
+        let sizes: Vec<u64> = self.sizes.iter().map(|r| r.get(&hello.rng)).collect();
+        log::debug!("[sizes] {:?} from {}", sizes, p);
+        for n in sizes {
+            p += n as usize;
+            hello.cut(p);
+        }
+    }
+}
+
+/// every even part (except the last) with the low ttl: lost and retransmitted later
+#[derive(Debug)]
+struct Disorder(u8);
+
+impl DesyncStep for Disorder {
+    fn before<'a>(&'a self, s: &'a TcpStream, part: Part<'a>) -> StepFuture<'a, bool> {
+        Box::pin(async move {
+            let ttl = if part.n.is_multiple_of(2) && !part.last { self.0 as u32 } else { part.ttl };
+            sys::set_ttl(s, ttl)?;
+            Ok(false)
+        })
+    }
+}
+
+/// Delay in ms: `10` or `rand(5,20)`
+#[derive(Debug, Clone, Copy)]
+pub struct Delay(Range);
+
+impl Delay {
+    pub fn get(&self, rng: &Rng) -> Duration {
+        Duration::from_millis(self.0.get(rng))
+    }
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self).map_err(|e| format!("delay {}", e))
+    }
+}
+
+/// pause after every part but the last, or after part n only
+#[derive(Debug)]
+struct Sleep {
+    delay: Delay,
+    part: Option<usize>,
+}
+
+impl DesyncStep for Sleep {
+    fn after<'a>(&'a self, _: &'a TcpStream, part: Part<'a>) -> StepFuture<'a, ()> {
+        Box::pin(async move {
+            if !part.last && self.part.is_none_or(|n| n == part.n) {
+                tokio::time::sleep(self.delay.get(&part.hello.rng)).await;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// ack step timeout without timeout=, ms
+pub const ACK_TIMEOUT: u64 = 500;
+const ACK_POLL: Duration = Duration::from_millis(1);
+
+/// After every part but the last wait until the server has acked all sent (TCP_INFO),
+/// so no two parts are coalesced into one segment. A part sent with the low ttl
+/// is never acked, the wait ends by the timeout.
+#[derive(Debug)]
+struct Ack(Duration);
+
+impl DesyncStep for Ack {
+    fn after<'a>(&'a self, s: &'a TcpStream, part: Part<'a>) -> StepFuture<'a, ()> {
+        Box::pin(async move {
+            if part.last {
+                return Ok(());
+            }
+            let start = Instant::now();
+            loop {
+                match sys::unacked(s) {
+                    Ok(0) => break,
+                    Ok(n) if start.elapsed() >= self.0 => {
+                        log::debug!("[ack] timeout, {} unacked", n);
+                        break;
+                    }
+                    Ok(_) => tokio::time::sleep(ACK_POLL).await,
+                    Err(e) => {
+                        log::debug!("[ack] {}", e);
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// TCP_MAXSEG: the kernel cuts the hello into small segments itself.
+/// The clamp is taken at connect, it stays for the whole connection
+/// (both ways: it is announced to the server), use it in a rule.
+#[derive(Debug)]
+struct Mss(u32);
+
+impl DesyncStep for Mss {
+    fn connect(&self, s: &TcpSocket) -> io::Result<()> {
+        sys::set_mss(s, self.0)
+    }
+}
+
+/// Tiny SO_RCVBUF: the small window in the SYN makes the server answer in small segments.
+/// The default size and autotuning are back before the relay
+#[derive(Debug)]
+struct Window(u32);
+
+impl DesyncStep for Window {
+    fn connect(&self, s: &TcpSocket) -> io::Result<()> {
+        s.set_recv_buffer_size(self.0)
+    }
+
+    fn done(&self, s: &TcpStream) -> io::Result<()> {
+        sys::reset_recv_buffer(s)
+    }
+}
+
+/// server name (Host value) case change. Before tlsrec
+#[derive(Debug)]
+struct CaseStep(Case);
+
+impl DesyncStep for CaseStep {
+    fn prepare(&self, hello: &mut Hello) {
+        if let Some((p1, p2)) = hello.layout.host {
+            self.0.apply(&mut hello.buf[p1..p2], &hello.rng);
+        }
+    }
+}
+
+/// "example.com." in the sni, all the lengths fixed. Before tlsrec
+#[derive(Debug)]
+struct Dot;
+
+impl DesyncStep for Dot {
+    fn prepare(&self, hello: &mut Hello) {
+        let Some((p1, p2)) = hello.layout.host.filter(|_| hello.layout.record.is_some()) else {
+            return;
+        };
+        let name = [&hello.buf[p1..p2], b"."].concat();
+        if let Some(buf) = tls::replace_sni(&hello.buf, &name) {
+            hello.buf = buf;
+            hello.moved(p2, 1);
+        }
+    }
+}
+
+/// a new TLS record at the position, tcp - and a new segment
+#[derive(Debug)]
+struct TlsRec {
+    at: Pos,
+    tcp: bool,
+}
+
+impl DesyncStep for TlsRec {
+    fn prepare(&self, hello: &mut Hello) {
+        if let Some(p) = hello.eval(&self.at).filter(|p| hello.split_record(*p)) {
+            if self.tcp {
+                hello.cut(p);
+            }
+        }
+    }
+}
+
+/// An urgent byte after the part ending at the position. One only:
+/// the receiver keeps just the last urgent mark, earlier bytes would go into the stream
+#[derive(Debug)]
+struct Oob {
+    at: Pos,
+    byte: u8,
+}
+
+impl DesyncStep for Oob {
+    fn prepare(&self, hello: &mut Hello) {
+        if let Some(p) = hello.eval(&self.at) {
+            hello.cut(p);
+        }
+    }
+
+    fn after<'a>(&'a self, s: &'a TcpStream, part: Part<'a>) -> StepFuture<'a, ()> {
+        Box::pin(async move {
+            if part.marked {
+                log::debug!("[oob] at {}", part.offset + part.data.len());
+                sys::set_ttl(s, part.ttl)?;
+                sys::send_oob(s, self.byte).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use crate::Result;
+use hickory_resolver::{
+    config::{LookupIpStrategy, ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+use rustls::ClientConfig;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{oneshot, Semaphore};
+
+type Waiters = Vec<oneshot::Sender<Vec<IpAddr>>>;
+
+/// max domains with a remembered address
+const PREFERRED_CAP: usize = 4096;
+
+/// DoH resolver shared by all connections.
+/// Lookups run in parallel, identical in-flight names are merged into one lookup.
+/// Both A and AAAA records are resolved.
+pub struct Dns {
+    resolver: TokioAsyncResolver,
+    inflight: Mutex<HashMap<String, Waiters>>,
+    limit: Semaphore,
+    // domain -> address the last connection succeeded to
+    preferred: Mutex<HashMap<String, IpAddr>>,
+}
+
+impl Dns {
+    /// limit - max concurrent lookups
+    pub fn new(limit: usize) -> Result<Arc<Self>> {
+        let root_store =
+            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let client_config = ClientConfig::builder_with_provider(
+            rustls::crypto::aws_lc_rs::default_provider().into(),
+        )
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+        let mut resolver_config = ResolverConfig::cloudflare_https();
+        resolver_config.set_tls_client_config(Arc::new(client_config));
+        let mut resolver_opts = ResolverOpts::default();
+        resolver_opts.cache_size = 1024;
+        resolver_opts.edns0 = true;
+        resolver_opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        let resolver = TokioAsyncResolver::tokio(resolver_config, resolver_opts);
+
+        Ok(Arc::new(Self {
+            resolver,
+            inflight: Default::default(),
+            limit: Semaphore::new(limit),
+            preferred: Default::default(),
+        }))
+    }
+
+    pub async fn resolve(self: &Arc<Self>, name: &str) -> Vec<IpAddr> {
+        let (tx, rx) = oneshot::channel();
+        let first = {
+            let mut inflight = self.inflight.lock().unwrap();
+            let waiters = inflight.entry(name.to_string()).or_default();
+            waiters.push(tx);
+            waiters.len() == 1
+        };
+
+        if first {
+            // the lookup lives in its own task, so a dropped caller does not stall the waiters
+            let dns = self.clone();
+            let name = name.to_string();
+            tokio::spawn(async move {
+                let ips = dns.lookup(&name).await;
+                let waiters = dns.inflight.lock().unwrap().remove(&name).unwrap_or_default();
+                for tx in waiters {
+                    let _ = tx.send(ips.clone());
+                }
+            });
+        } else {
+            log::trace!("dns merge in-flight: {}", name);
+        }
+
+        rx.await.unwrap_or_default()
+    }
+
+    pub fn preferred(&self, name: &str) -> Option<IpAddr> {
+        self.preferred.lock().unwrap().get(name).copied()
+    }
+
+    /// remember the address that worked for the domain
+    pub fn remember(&self, name: &str, ip: IpAddr) {
+        let mut preferred = self.preferred.lock().unwrap();
+        if preferred.len() >= PREFERRED_CAP && !preferred.contains_key(name) {
+            preferred.clear();
+        }
+        preferred.insert(name.to_string(), ip);
+    }
+
+    async fn lookup(&self, name: &str) -> Vec<IpAddr> {
+        let Ok(_permit) = self.limit.acquire().await else {
+            return Vec::new();
+        };
+        match self.resolver.lookup_ip(name).await {
+            Ok(response) => response.iter().collect(),
+            Err(e) => {
+                log::error!("dns resolver: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::{fmt, io};
+
+/// Failures before the tunnel is established, the client gets an http error response
+/// or a SOCKS reply
+#[derive(Debug)]
+pub enum ProxyError {
+    BadRequest(String),
+    Dns(String),
+    Connect(io::Error),
+    ConnectTimeout,
+    Denied(String),
+}
+
+impl ProxyError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "400 Bad Request",
+            Self::Denied(_) => "403 Forbidden",
+            Self::Dns(_) | Self::Connect(_) => "502 Bad Gateway",
+            Self::ConnectTimeout => "504 Gateway Timeout",
+        }
+    }
+
+    /// SOCKS5 REP code
+    pub fn socks_reply(&self) -> u8 {
+        match self {
+            Self::BadRequest(_) => 0x01,
+            Self::Denied(_) => 0x02,
+            Self::Dns(_) => 0x04,
+            Self::Connect(_) => 0x05,
+            Self::ConnectTimeout => 0x06,
+        }
+    }
+
+    pub fn response(&self) -> Vec<u8> {
+        let body = format!("fdpi: {}\r\n", self);
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status(),
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Self::ConnectTimeout,
+            _ => Self::Connect(e),
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadRequest(e) => write!(f, "bad request: {}", e),
+            Self::Dns(domain) => write!(f, "dns resolve failed: {}", domain),
+            Self::Connect(e) => write!(f, "connect failed: {}", e),
+            Self::ConnectTimeout => write!(f, "connect timeout"),
+            Self::Denied(e) => write!(f, "denied: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
@@ -0,0 +1,63 @@
+use crate::{
+    desync::{DesyncStep, Part, StepFuture},
+    sys, tls,
+};
+use std::{io, time::Duration};
+use tokio::net::TcpStream;
+
+/// time for the fake to leave the host before its bytes are replaced with the real ones
+pub const FAKE_WAIT: Duration = Duration::from_millis(20);
+
+/// Decoy ClientHello sent with a low TTL: it reaches the DPI, not the server
+#[derive(Debug)]
+pub struct Fake {
+    /// how many first parts of the hello go after a fake
+    pub count: u8,
+    pub ttl: u8,
+    /// allowed server name for the fake
+    pub sni: String,
+    /// fake payload from a file, otherwise the real hello with the sni replaced
+    pub payload: Option<Vec<u8>>,
+}
+
+impl Fake {
+    /// None - the sni can not be replaced, a fake would carry the real one
+    pub fn payload(&self, hello: &[u8]) -> Option<Vec<u8>> {
+        match &self.payload {
+            Some(p) => Some(p.clone()),
+            None => tls::replace_sni(hello, self.sni.as_bytes()),
+        }
+    }
+}
+
+impl DesyncStep for Fake {
+    /// the first count parts go after a fake
+    fn before<'a>(&'a self, s: &'a TcpStream, part: Part<'a>) -> StepFuture<'a, bool> {
+        Box::pin(async move {
+            if part.n >= self.count as usize {
+                return Ok(false);
+            }
+            let Some(payload) = self.payload(&part.hello.original) else {
+                log::info!("[fake] no sni to replace, the fake is skipped");
+                return Ok(false);
+            };
+            let seg = segment(&payload, part.offset, part.data.len());
+            match sys::send_fake(s, &seg, part.data, self.ttl as u32, part.ttl).await {
+                Ok(()) => Ok(true),
+                // nothing sent yet, the part goes as usual
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    log::info!("[fake] not supported here, the fake is skipped");
+                    Ok(false)
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+/// fake bytes at the stream offset of a part, zero padded to its length
+pub fn segment(payload: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    let mut r: Vec<u8> = payload.iter().skip(offset).take(len).copied().collect();
+    r.resize(len, 0);
+    r
+}
@@ -0,0 +1,290 @@
+use crate::{error::ProxyError, Result};
+use bytes::BytesMut;
+use std::net::{IpAddr, Ipv6Addr};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// max http head size
+const HEAD_CAP: usize = 8192;
+
+/// Evasions for plain http, applied to the request before forwarding
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HttpTricks {
+    /// "hOsT:" header name
+    pub host_mixcase: bool,
+    /// "Host:  example.com"
+    pub host_space: bool,
+    /// "Host:example.com"
+    pub host_nospace: bool,
+    /// send the request in two segments split in the middle of the Host value
+    pub host_split: bool,
+    /// "GeT"
+    pub method_mixcase: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct HttpHead<'a> {
+    pub command: &'a [u8],
+    pub authority: &'a str,
+    /// ascii host name (punycode for IDN) or ip literal
+    pub domain: String,
+    /// set for ip literal, dns is not needed
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    /// origin-form target of a plain http request, empty for CONNECT
+    pub path: String,
+    pub method: &'a [u8],
+}
+
+/// Reads until the end of the http head (\r\n\r\n) and returns its length,
+/// bytes after the head stay in buf. None - closed before any byte.
+pub async fn read_http_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+) -> Result<Option<usize>> {
+    loop {
+        if let Some(p) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(p + 4));
+        }
+        if buf.len() >= HEAD_CAP {
+            return Err(ProxyError::BadRequest("http head too large".into()).into());
+        }
+        let n = reader.read_buf(buf).await?;
+        log::trace!("read {} bytes", n);
+        if n == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            log::trace!("connection reset by peer");
+            return Err(ProxyError::BadRequest("incomplete http head".into()).into());
+        }
+    }
+}
+
+pub fn parse_http_head(input: &[u8]) -> Result<HttpHead<'_>> {
+    let mut r: HttpHead = Default::default();
+    let first_string = input.split(|x| *x == b'\r').next().ok_or("err")?;
+    log::info!("http head: {:?}", String::from_utf8_lossy(first_string));
+    let mut it = first_string.split(|x| *x == b' ');
+    r.command = it.next().ok_or("err")?;
+    let target = std::str::from_utf8(it.next().ok_or("err")?)?;
+    let default_port = if r.command == b"CONNECT" {
+        r.authority = target;
+        443
+    } else {
+        // absolute-form: http://authority/path?query
+        let rest = target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .map(|_| &target[7..])
+            .ok_or("err absolute uri")?;
+        let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+        r.authority = authority;
+        r.path = match path {
+            "" => "/".into(),
+            p if p.starts_with('?') => format!("/{}", p),
+            p => p.into(),
+        };
+        80
+    };
+    (r.domain, r.ip, r.port) = parse_authority(r.authority, default_port)?;
+    r.method = it.next().ok_or("err")?;
+    log::trace!("end_http head: {:?}", r);
+    Ok(r)
+}
+
+/// host[:port], where host is a name, an IPv4 literal or a bracketed IPv6 literal
+pub fn parse_authority(input: &str, default_port: u16) -> Result<(String, Option<IpAddr>, u16)> {
+    if let Some(rest) = input.strip_prefix('[') {
+        let (host, tail) = rest.split_once(']').ok_or("err ipv6 literal")?;
+        let port = match tail {
+            "" => default_port,
+            _ => parse_port(tail.strip_prefix(':').ok_or("err authority")?)?,
+        };
+        let ip: Ipv6Addr = host.parse()?;
+        return Ok((ip.to_string(), Some(ip.into()), port));
+    }
+
+    let (host, port) = match input.rsplit_once(':') {
+        Some((host, port)) => (host, parse_port(port)?),
+        None => (input, default_port),
+    };
+    if host.contains(':') {
+        return Err("err authority".into());
+    }
+    let (domain, ip) = parse_host(host)?;
+    Ok((domain, ip, port))
+}
+
+/// ip literal or host name, IDN goes to punycode
+pub fn parse_host(host: &str) -> Result<(String, Option<IpAddr>)> {
+    if host.is_empty() {
+        return Err("err empty host".into());
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok((ip.to_string(), Some(ip)));
+    }
+    let domain = idna::domain_to_ascii(host).map_err(|_| "err idna host")?;
+    Ok((domain, None))
+}
+
+fn parse_port(input: &str) -> Result<u16> {
+    match input.parse::<u16>()? {
+        0 => Err("err port".into()),
+        port => Ok(port),
+    }
+}
+
+/// Host header value of an origin-form request (transparent mode)
+pub fn find_host(buf: &[u8]) -> Option<String> {
+    let (p1, p2) = host_point(buf)?;
+    let value = std::str::from_utf8(&buf[p1..p2]).ok()?;
+    parse_authority(value, 80).ok().map(|(domain, _, _)| domain)
+}
+
+/// Host header value position in the request head
+pub fn host_point(buf: &[u8]) -> Option<(usize, usize)> {
+    let end = buf.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let mut start = 0;
+    for line in buf[..end].split(|b| *b == b'\n') {
+        let line_start = start;
+        start += line.len() + 1;
+        let Some(p) = line.iter().position(|b| *b == b':') else {
+            continue;
+        };
+        // the request line
+        if line_start == 0 || !line[..p].eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+        let value = &line[p + 1..];
+        let p1 = line_start + p + 1 + value.len() - value.trim_ascii_start().len();
+        return Some((p1, p1 + value.trim_ascii().len()));
+    }
+    None
+}
+
+/// Absolute-form request head -> origin-form with tricks applied.
+/// Returns the request and the position to split it at (0 - no split).
+pub fn build_request(head: &HttpHead, raw: &[u8], tricks: HttpTricks) -> (Vec<u8>, usize) {
+    let mut lines = raw
+        .split(|b| *b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l));
+    lines.next();
+
+    let mut host: &[u8] = head.authority.as_bytes();
+    let mut headers = Vec::with_capacity(raw.len());
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = match line.iter().position(|b| *b == b':') {
+            Some(p) => (&line[..p], line[p + 1..].trim_ascii()),
+            None => (line, &[][..]),
+        };
+        if name.eq_ignore_ascii_case(b"host") {
+            host = value;
+            continue;
+        }
+        if name.eq_ignore_ascii_case(b"connection")
+            || name.eq_ignore_ascii_case(b"proxy-connection")
+            || name.eq_ignore_ascii_case(b"proxy-authorization")
+        {
+            continue;
+        }
+        headers.extend_from_slice(line);
+        headers.extend_from_slice(b"\r\n");
+    }
+
+    let mut r = Vec::with_capacity(raw.len() + 32);
+    match tricks.method_mixcase {
+        true => r.extend(mix_case(head.command)),
+        false => r.extend_from_slice(head.command),
+    }
+    r.push(b' ');
+    r.extend_from_slice(head.path.as_bytes());
+    r.push(b' ');
+    r.extend_from_slice(head.method);
+    r.extend_from_slice(b"\r\n");
+
+    r.extend_from_slice(if tricks.host_mixcase { b"hOsT" } else { b"Host" });
+    let sep: &[u8] = match (tricks.host_nospace, tricks.host_space) {
+        (true, _) => b":",
+        (false, true) => b":  ",
+        _ => b": ",
+    };
+    r.extend_from_slice(sep);
+    let split = if tricks.host_split { r.len() + host.len() / 2 } else { 0 };
+    r.extend_from_slice(host);
+    r.extend_from_slice(b"\r\n");
+
+    r.extend_from_slice(&headers);
+    // one request per connection, the next one comes in absolute-form again
+    r.extend_from_slice(b"Connection: close\r\n\r\n");
+    (r, split)
+}
+
+pub async fn send_request(writer: &mut TcpStream, req: &[u8], split: usize) -> Result<()> {
+    if split == 0 || split >= req.len() {
+        writer.write_all(req).await?;
+        return Ok(());
+    }
+    writer.set_nodelay(true)?;
+    writer.write_all(&req[..split]).await?;
+    writer.write_all(&req[split..]).await?;
+    writer.set_nodelay(false)?;
+    Ok(())
+}
+
+fn mix_case(s: &[u8]) -> Vec<u8> {
+    s.iter()
+        .enumerate()
+        .map(|(i, b)| match i % 2 {
+            0 => b.to_ascii_uppercase(),
+            _ => b.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authority() {
+        let ip6: IpAddr = "2001:db8::1".parse().unwrap();
+        let (host, ip, port) = parse_authority("[2001:db8::1]:443", 80).unwrap();
+        assert_eq!((host.as_str(), ip, port), ("2001:db8::1", Some(ip6), 443));
+        let (host, ip, port) = parse_authority("[::1]", 80).unwrap();
+        assert_eq!((host.as_str(), ip, port), ("::1", Some("::1".parse().unwrap()), 80));
+        let (host, ip, port) = parse_authority("1.2.3.4:443", 80).unwrap();
+        assert_eq!((host.as_str(), ip, port), ("1.2.3.4", Some("1.2.3.4".parse().unwrap()), 443));
+        let (host, ip, port) = parse_authority("пример.рф:8443", 80).unwrap();
+        assert_eq!((host.as_str(), ip, port), ("xn--e1afmkfd.xn--p1ai", None, 8443));
+    }
+
+    #[test]
+    fn bad_authority() {
+        for input in [
+            "2001:db8::1",
+            "2001:db8::1:443",
+            "[2001:db8::1]x",
+            "[2001:db8::1]:443x",
+            "[2001:db8::1",
+            "[example.com]:443",
+            "example.com:0",
+            "example.com:",
+            "example.com:65536",
+            ":443",
+        ] {
+            assert!(parse_authority(input, 80).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn host() {
+        assert_eq!(parse_host("Example.COM").unwrap(), ("example.com".to_string(), None));
+        assert_eq!(parse_host("bücher.de").unwrap(), ("xn--bcher-kva.de".to_string(), None));
+        let (host, ip) = parse_host("10.0.0.1").unwrap();
+        assert_eq!((host.as_str(), ip), ("10.0.0.1", Some("10.0.0.1".parse().unwrap())));
+        assert!(parse_host("").is_err());
+    }
+}
@@ -1,14 +1,14 @@
 use bytes::BytesMut;
+use auto::Auto;
 use clap::Parser;
-use hickory_proto;
-use hickory_proto::rr::rdata::a::A as dns_A;
-use hickory_resolver::{
-    config::{ResolverConfig, ResolverOpts},
-    TokioAsyncResolver,
-};
-use log;
-use pretty_env_logger;
-use rustls::ClientConfig;
+use dns::Dns;
+use error::ProxyError;
+use desync::{Delay, Strategy};
+use http::{parse_http_head, HttpTricks};
+use mutate::Case;
+use pos::Pos;
+use rng::Range;
+use rules::{Action, Rules};
 use std::{
     borrow::Borrow,
     error::Error,
@@ -17,16 +17,28 @@ use std::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
+    time::Duration,
 };
 use tokio::{
-    io::{copy_bidirectional_with_sizes, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::{mpsc, oneshot},
+    io::{copy_bidirectional_with_sizes, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
 };
-use webpki_roots;
 use take_sni::take_sni_point;
-use parcelona::parser_combinators::split_at_revers;
 //mod util;
+mod auto;
+mod connect;
+mod desync;
+mod dns;
+mod error;
+mod fake;
+mod http;
+mod mutate;
+mod pos;
+mod rules;
+mod rng;
+mod socks;
+mod sys;
+mod tls;
 
 #[derive(Parser, Debug)]
 #[command(name = "fdpi")]
@@ -41,41 +53,257 @@ struct Cli {
     /// Log mode disable
     #[arg(short, long, default_value_t = false)]
     nolog: bool,
-    /// [example: -b4 -b2] range 1..128
-    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..128))]
-    body: Vec<u8>,
-    /// [example: -s2 -s4] range 1..128
-    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..128))]
-    sni: Vec<u8>,
-    /// edit sni
+    /// [example: -b4 -b 'rand(1,8)'] range 1..128, rand - new for every connection
+    #[arg(short, long, value_parser = size_arg)]
+    body: Vec<Range>,
+    /// [example: -s2 -s 'rand(2,6)'] range 1..128, rand - new for every connection
+    #[arg(short, long, value_parser = size_arg)]
+    sni: Vec<Range>,
+    /// edit sni: the case of the first and last letters (or --case)
     #[arg(short, long, default_value_t = false)]
     esni: bool,
+    /// sni case: random, alternate, firstlast, upper
+    #[arg(long, value_parser = clap::value_parser!(Case))]
+    case: Option<Case>,
+    /// sni with a trailing dot
+    #[arg(long, default_value_t = false)]
+    sni_dot: bool,
     /// ttl for disorder range 1..64
     #[arg(short, long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..))]
     ttl: u8,
+    /// max concurrent dns lookups
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u16).range(1..))]
+    dns_limit: u16,
+    /// connect timeout per address, ms
+    #[arg(long, default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: u64,
+    /// wait for the complete ClientHello, ms
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    hello_timeout: u64,
+    /// plain http: "hOsT:" header name
+    #[arg(long, default_value_t = false)]
+    host_mixcase: bool,
+    /// plain http: extra space after "Host:"
+    #[arg(long, default_value_t = false)]
+    host_space: bool,
+    /// plain http: no space after "Host:"
+    #[arg(long, default_value_t = false)]
+    host_nospace: bool,
+    /// plain http: split the request at the Host value
+    #[arg(long, default_value_t = false)]
+    host_split: bool,
+    /// plain http: mixed-case method
+    #[arg(long, default_value_t = false)]
+    method_mixcase: bool,
+    /// SOCKS4/5 only port, SOCKS is also detected on the main port
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    socks_port: Option<u16>,
+    /// SOCKS5 username/password auth
+    #[arg(long, requires = "socks_pass")]
+    socks_user: Option<String>,
+    #[arg(long, requires = "socks_user")]
+    socks_pass: Option<String>,
+    /// transparent port for iptables REDIRECT (linux)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    redirect_port: Option<u16>,
+    /// transparent port for TPROXY, IP_TRANSPARENT (linux)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    tproxy_port: Option<u16>,
+    /// TPROXY: connect to the server from the client address
+    #[arg(long, default_value_t = false, requires = "tproxy_port")]
+    keep_source: bool,
+    /// send a fake hello before each of the first N parts (linux)
+    #[arg(long, default_value_t = 0)]
+    fake: u8,
+    /// ttl for the fake, reaches the DPI but not the server
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..))]
+    fake_ttl: u8,
+    /// server name in the fake hello
+    #[arg(long, default_value = "www.google.com")]
+    fake_sni: String,
+    /// fake payload from a file instead of the hello with --fake-sni
+    #[arg(long)]
+    fake_file: Option<std::path::PathBuf>,
+    /// [example: --split sni+1 --split host_mid --split -10 --split rand(1,8)]
+    /// split positions: [sni|sni_end|sni_mid|host..|record][+-N|rand(a,b)], -N from the end
+    #[arg(long, allow_hyphen_values = true, value_parser = pos_arg)]
+    split: Vec<String>,
+    /// [example: --tlsrec sni+1] split into TLS records at the positions
+    #[arg(long, allow_hyphen_values = true, value_parser = pos_arg)]
+    tlsrec: Vec<String>,
+    /// also send every TLS record in its own segment
+    #[arg(long, default_value_t = false, requires = "tlsrec")]
+    tlsrec_tcp: bool,
+    /// send an urgent (MSG_OOB) byte inside the hello
+    #[arg(long, default_value_t = false)]
+    oob: bool,
+    /// oob position
+    #[arg(long, default_value = "sni+1", allow_hyphen_values = true, value_parser = pos_arg)]
+    oob_at: String,
+    /// oob byte value
+    #[arg(long, default_value_t = b'a')]
+    oob_byte: u8,
+    /// send the parts with the normal ttl, no disorder
+    #[arg(long, default_value_t = false)]
+    no_disorder: bool,
+    /// [example: split:sni+1,sizes:2:rand(1,3):from=sni,disorder:ttl=2,fake:ttl=3:count=1,tlsrec:sni:tcp,oob:sni+1,sleep:rand(5,20),ack,mss:200,window:1024,case:random,dot]
+    /// desync steps in order, replaces the flags above
+    #[arg(long, value_parser = clap::value_parser!(Strategy))]
+    strategy: Option<Strategy>,
+    /// pause between the parts, ms: 10 or rand(5,20)
+    #[arg(long, value_parser = delay_arg)]
+    delay: Option<String>,
+    /// wait until every part is acked before the next one (linux)
+    #[arg(long, default_value_t = false)]
+    wait_ack: bool,
+    /// fixed seed for the rand() values: the n-th hello gets the same positions and delays every run
+    #[arg(long)]
+    seed: Option<u64>,
+    /// clamp the upstream MSS: the kernel sends the hello in small segments.
+    /// Lasts the whole connection, both ways (fixed at the handshake): better as mss:N in a rule
+    #[arg(long, value_parser = clap::value_parser!(u32).range(88..))]
+    mss: Option<u32>,
+    /// upstream receive buffer while the hello is sent: a small window, the server answers in small segments
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    window: Option<u32>,
+    /// per-domain rules file: pattern [ports] direct|block|strategy
+    #[arg(long)]
+    rules: Option<std::path::PathBuf>,
+    /// find a working strategy per domain (for domains without a rule)
+    #[arg(long, default_value_t = false)]
+    auto: bool,
+    /// auto: candidate strategy, in order [default: the global one and built-in]
+    #[arg(long, value_parser = clap::value_parser!(Strategy), requires = "auto")]
+    auto_try: Vec<Strategy>,
+    /// auto: wait for the ServerHello, ms
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    auto_timeout: u64,
+    /// auto: a found strategy not verified for this long can be dropped when the cache is full, s
+    #[arg(long, default_value_t = 3600)]
+    auto_expiry: u64,
+    /// auto: file to keep the found strategies in across restarts
+    #[arg(long, requires = "auto")]
+    auto_store: Option<std::path::PathBuf>,
+    /// auto: save the store every N s (and on exit)
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    auto_flush: u64,
+    /// strategy to reconnect with on RST or silence before the client got any byte, in order
+    #[arg(long, value_parser = clap::value_parser!(Strategy))]
+    fallback: Vec<Strategy>,
+    /// fallback: wait for the first server bytes, ms
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    retry_timeout: u64,
 }
 
 fn str_to_ip(i: &str) -> std::result::Result<IpAddr, AddrParseError> {
     i.parse()
 }
 
+fn pos_arg(i: &str) -> std::result::Result<String, String> {
+    i.parse::<Pos>().map(|_| i.to_string())
+}
+
+fn size_arg(i: &str) -> std::result::Result<Range, String> {
+    let r: Range = i.parse()?;
+    match r.0 >= 1 && r.1 < 128 {
+        true => Ok(r),
+        false => Err("range 1..128".into()),
+    }
+}
+
+fn delay_arg(i: &str) -> std::result::Result<String, String> {
+    i.parse::<Delay>().map(|_| i.to_string())
+}
+
+/// -b/-s and the other desync flags as a strategy string
+fn legacy_strategy(cli: &Cli) -> String {
+    let mut steps: Vec<String> = Vec::new();
+    match (&cli.case, cli.esni) {
+        (Some(c), _) => steps.push(format!("case:{:?}", c).to_ascii_lowercase()),
+        (None, true) => steps.push("case:firstlast".into()),
+        _ => {}
+    }
+    if cli.sni_dot {
+        steps.push("dot".into());
+    }
+    let sizes = |v: &[Range]| v.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(":");
+    if !cli.body.is_empty() {
+        steps.push(format!("sizes:{}", sizes(&cli.body)));
+    }
+    steps.push("split:sni".into());
+    if !cli.sni.is_empty() {
+        steps.push(format!("sizes:{}:from=sni", sizes(&cli.sni)));
+    }
+    steps.extend(cli.split.iter().map(|p| format!("split:{}", p)));
+    let tcp = if cli.tlsrec_tcp { ":tcp" } else { "" };
+    steps.extend(cli.tlsrec.iter().map(|p| format!("tlsrec:{}{}", p, tcp)));
+    if cli.oob {
+        steps.push(format!("oob:at={}:byte={}", cli.oob_at, cli.oob_byte));
+    }
+    if cli.fake > 0 {
+        let mut fake = format!("fake:count={}:ttl={}:sni={}", cli.fake, cli.fake_ttl, cli.fake_sni);
+        if let Some(f) = &cli.fake_file {
+            fake += &format!(":file={}", f.display());
+        }
+        steps.push(fake);
+    }
+    if !cli.no_disorder {
+        steps.push(format!("disorder:ttl={}", cli.ttl));
+    }
+    if let Some(d) = &cli.delay {
+        steps.push(format!("sleep:{}", d));
+    }
+    if cli.wait_ack {
+        steps.push("ack".into());
+    }
+    if let Some(m) = cli.mss {
+        steps.push(format!("mss:{}", m));
+    }
+    if let Some(w) = cli.window {
+        steps.push(format!("window:{}", w));
+    }
+    steps.join(",")
+}
+
 const CONN_ESTABL: &[u8; 31] = b" 200 Connection Established\r\n\r\n";
-const CONN_CLOSE: &[u8; 39] = b" HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n";
 
-type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+pub(crate) type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
-#[derive(Debug, Default)]
-struct HttpHead<'a> {
-    command: &'a [u8],
+/// Where a tunnel goes, enough to connect again
+struct Target<'a> {
     domain: &'a str,
+    /// ip literal, no dns
+    ip: Option<IpAddr>,
     port: u16,
-    method: &'a [u8],
+    /// connect from this (client) address
+    source: Option<IpAddr>,
+}
+
+/// Settings shared by all connections
+struct Ctx {
+    dns: Arc<Dns>,
+    strategy: Strategy,
+    rules: Rules,
+    auto: Option<Auto>,
+    fallback: Vec<Strategy>,
+    retry_timeout: Duration,
+    connect_timeout: Duration,
+    hello_timeout: Duration,
+    http_tricks: HttpTricks,
+    socks_auth: Option<(String, String)>,
+    keep_source: bool,
 }
 
-type Responder = (
-    String,
-    oneshot::Sender<Option<hickory_proto::rr::rdata::a::A>>,
-);
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// http proxy, SOCKS4/5 detected by the first byte
+    Http,
+    Socks,
+    /// iptables REDIRECT, the destination from SO_ORIGINAL_DST
+    Redirect,
+    /// TPROXY, the destination is the local address
+    Tproxy,
+}
 
 fn error_handling(x: Result<()>) {
     if x.is_err() {
@@ -83,65 +311,23 @@ fn error_handling(x: Result<()>) {
     }
 }
 
-async fn dns_resolver(mut rx: mpsc::Receiver<Responder>) -> Result<()> {
-    let root_store =
-        rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let client_config =
-        ClientConfig::builder_with_provider(rustls::crypto::aws_lc_rs::default_provider().into())
-            .with_protocol_versions(&[&rustls::version::TLS13])?
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-
-    let mut resolver_config = ResolverConfig::cloudflare_https();
-    resolver_config.set_tls_client_config(Arc::new(client_config));
-    let mut resolver_opts = ResolverOpts::default();
-    resolver_opts.cache_size = 1024;
-    resolver_opts.edns0 = true;
-    let resolver = TokioAsyncResolver::tokio(resolver_config, resolver_opts);
-
-    while let Some(domain) = rx.recv().await {
-        let ip: Option<dns_A>;
-        let (dname, tx) = domain;
-        let response = resolver
-            .ipv4_lookup(dname)
-            .await
-            .map_err(|e| {
-                log::error!("dns resolver: {}", e);
-                e
-            })
-            .ok();
-        if response.is_none() {
-            ip = None;
-        } else {
-            ip = response.unwrap().iter().next().copied();
-        }
-
-        let _ = tx.send(ip);
-    }
-
-    Ok(())
-}
-
-async fn tcp_server(
-    tx: mpsc::Sender<Responder>,
-    addr: SocketAddr,
-    fdpi_methods: (Vec<u8>, Vec<u8>, u8, bool),
-) -> Result<()> {
+async fn tcp_server(ctx: Arc<Ctx>, addr: SocketAddr, mode: Mode) -> Result<()> {
     // counter
     let num_conns: Arc<AtomicU64> = Default::default();
-    let listener = TcpListener::bind(addr).await?;
-    log::info!("sever start");
+    let listener = match mode {
+        Mode::Tproxy => sys::transparent_listener(addr)?,
+        _ => TcpListener::bind(addr).await?,
+    };
+    log::info!("sever start {:?}: {}", mode, addr);
 
     loop {
         let (mut socket, _) = listener.accept().await?;
-        let tx_new = tx.clone();
+        let ctx = ctx.clone();
         num_conns.fetch_add(1, Ordering::SeqCst);
         let num_conns = num_conns.clone();
-        let fdpi_methods = fdpi_methods.clone();
         tokio::spawn(async move {
-            let e = process(&mut socket, tx_new, fdpi_methods).await;
+            let e = serve(&mut socket, &ctx, mode, addr.port()).await;
             error_handling(e);
-            let _ = socket.write(CONN_CLOSE).await;
             //let _ = socket.shutdown().await;
             num_conns.fetch_sub(1, Ordering::SeqCst);
             log::info!("count opened sockets: {}", num_conns.load(Ordering::SeqCst));
@@ -159,139 +345,450 @@ fn main() {
     pretty_env_logger::init();
     println!("{:} --help", clap::crate_name!());
 
-    let fdm = (cli.body, cli.sni, cli.ttl, cli.esni);
-    log::trace!("read fdm: {:#?}", fdm);
+    let strategy = match &cli.strategy {
+        Some(s) => s.clone(),
+        None => legacy_strategy(&cli).parse().expect("strategy"),
+    };
+    log::info!("strategy: {}", strategy);
+    log::trace!("steps: {:#?}", strategy);
+    if let Some(seed) = cli.seed {
+        rng::set_seed(seed);
+        log::info!("seed: {}", seed);
+    }
+    let rules = match &cli.rules {
+        Some(path) => Rules::load(path).expect("rules"),
+        None => Rules::default(),
+    };
+    log::trace!("rules: {:#?}", rules);
+    let auto = cli.auto.then(|| {
+        let candidates = match cli.auto_try.is_empty() {
+            true => std::iter::once(strategy.clone())
+                .chain(auto::CANDIDATES.iter().map(|s| s.parse().expect("auto candidate")))
+                .collect(),
+            false => cli.auto_try.clone(),
+        };
+        Auto::new(
+            candidates,
+            Duration::from_millis(cli.auto_timeout),
+            Duration::from_secs(cli.auto_expiry),
+            cli.auto_store.clone(),
+        )
+    });
 
     let rt = tokio::runtime::Runtime::new().unwrap();
     let _guard = rt.enter();
-    let (tx, rx) = mpsc::channel::<Responder>(16);
-    let addr = SocketAddr::from((cli.addr, cli.port));
-
-    rt.spawn(async {
-        let e = dns_resolver(rx).await;
-        error_handling(e);
+    let ctx = Arc::new(Ctx {
+        dns: Dns::new(cli.dns_limit as usize).expect("dns resolver"),
+        strategy,
+        rules,
+        auto,
+        fallback: cli.fallback,
+        retry_timeout: Duration::from_millis(cli.retry_timeout),
+        connect_timeout: Duration::from_millis(cli.connect_timeout),
+        hello_timeout: Duration::from_millis(cli.hello_timeout),
+        http_tricks: HttpTricks {
+            host_mixcase: cli.host_mixcase,
+            host_space: cli.host_space,
+            host_nospace: cli.host_nospace,
+            host_split: cli.host_split,
+            method_mixcase: cli.method_mixcase,
+        },
+        socks_auth: cli.socks_user.zip(cli.socks_pass),
+        keep_source: cli.keep_source,
     });
+    let addr = SocketAddr::from((cli.addr, cli.port));
+    let listeners = [
+        (cli.socks_port, Mode::Socks),
+        (cli.redirect_port, Mode::Redirect),
+        (cli.tproxy_port, Mode::Tproxy),
+    ];
 
     rt.block_on(async {
-        let e = tcp_server(tx, addr, fdm).await;
-        error_handling(e);
+        for (port, mode) in listeners {
+            let Some(port) = port else { continue };
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let e = tcp_server(ctx, SocketAddr::from((cli.addr, port)), mode).await;
+                error_handling(e);
+            });
+        }
+        if ctx.auto.is_some() && cli.auto_store.is_some() {
+            let ctx = ctx.clone();
+            let period = Duration::from_secs(cli.auto_flush);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(period).await;
+                    if let Some(auto) = &ctx.auto {
+                        auto.save();
+                    }
+                }
+            });
+        }
+
+        tokio::select! {
+            e = tcp_server(ctx.clone(), addr, Mode::Http) => error_handling(e),
+            _ = shutdown_signal() => log::info!("shutdown"),
+        }
+        if let Some(auto) = &ctx.auto {
+            auto.save();
+        }
     });
 }
 
-async fn process(
-    mut socket: &mut TcpStream,
-    tx: mpsc::Sender<Responder>,
-    fdpi_methods: (Vec<u8>, Vec<u8>, u8, bool),
-) -> Result<()> {
-    let mut buffer = BytesMut::with_capacity(1024);
-    let n = socket.read_buf(&mut buffer).await?;
-    log::trace!("read {} bytes", n);
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut term) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-    if n == 0 {
-        if !buffer.is_empty() {
-            log::trace!("connection reset by peer");
+async fn serve(socket: &mut TcpStream, ctx: &Ctx, mode: Mode, port: u16) -> Result<()> {
+    match mode {
+        Mode::Redirect => {
+            let dst = sys::original_dst(socket)?;
+            if dst == socket.local_addr()? {
+                return Err("transparent: connection to the proxy itself".into());
+            }
+            return process_transparent(socket, ctx, dst, None).await;
         }
+        Mode::Tproxy => {
+            let dst = socket.local_addr()?;
+            // a client talking to the listener itself, not intercepted traffic
+            if dst.port() == port && sys::is_local(dst.ip()) {
+                return Err("transparent: connection to the proxy itself".into());
+            }
+            let source = ctx.keep_source.then(|| socket.peer_addr()).transpose()?;
+            return process_transparent(socket, ctx, dst, source.map(|a| a.ip())).await;
+        }
+        _ => {}
+    }
+    let mut first = [0u8; 1];
+    if mode == Mode::Http {
+        socket.peek(&mut first).await?;
+    }
+    if mode == Mode::Socks || first[0] == socks::SOCKS5 || first[0] == socks::SOCKS4 {
+        return process_socks(socket, ctx).await;
+    }
+
+    let e = process(socket, ctx).await;
+    if let Some(pe) = e.as_ref().err().and_then(|e| e.downcast_ref::<ProxyError>()) {
+        log::info!("{}", pe);
+        let _ = socket.write_all(&pe.response()).await;
+    }
+    e
+}
+
+async fn process(mut socket: &mut TcpStream, ctx: &Ctx) -> Result<()> {
+    let mut buffer = BytesMut::with_capacity(1024);
+    let Some(head_len) = http::read_http_head(&mut socket, &mut buffer).await? else {
         return Ok(());
+    };
+    // pipelined bytes after the head, the ClientHello begins there
+    let rest = buffer.split_off(head_len);
+    if !rest.is_empty() {
+        log::trace!("{} bytes after http head", rest.len());
     }
 
     let addr = parse_http_head(buffer.borrow()).map_err(|e| {
         log::info!("error parse http head {}", e);
-        e
+        ProxyError::BadRequest(e.to_string())
     })?;
-    let (resp_tx, resp_rx) = oneshot::channel::<Option<dns_A>>();
-    tx.send((addr.domain.to_string(), resp_tx)).await?;
-    let ip: dns_A = resp_rx.await?.ok_or("not resolve dns to ip").map_err(|e| {
-        log::error!("{}", e);
-        e
-    })?;
-
-    if ip.is_loopback() {
-        log::info!("loopback connection close");
-        return Ok(());
+    let target = Target {
+        domain: &addr.domain,
+        ip: addr.ip,
+        port: addr.port,
+        source: None,
+    };
+    let strategy = match addr.command == b"CONNECT" {
+        true => first_strategy(ctx, &target),
+        false => Strategy::default(),
+    };
+    let mut server_con = connect_target(ctx, &target, &strategy).await?;
+
+    if addr.command == b"CONNECT" {
+        log::trace!("create tunnel");
+        socket
+            .write_all(&[addr.method, CONN_ESTABL].concat())
+            .await?;
+        // past the 200 the client speaks TLS: a failure closes the tunnel, no http response
+        tunnel(socket, &mut server_con, rest, ctx, &target)
+            .await
+            .map_err(|e| e.to_string())?;
+    } else {
+        let (req, split) = http::build_request(&addr, &buffer, ctx.http_tricks);
+        log::debug!("[http] {:?}", String::from_utf8_lossy(&req));
+        http::send_request(&mut server_con, &req, split).await?;
+        server_con.write_all(&rest).await?;
+        copy_bidirectional_with_sizes(&mut server_con, socket, 128, 128).await?;
     }
+    log::info!("socket close: {}", addr.domain);
 
-    let mut server_con = TcpStream::connect(SocketAddr::from((ip.octets(), addr.port))).await?;
+    Ok(())
+}
 
-    log::trace!("create tunnel");
-    socket
-        .write_all(&[addr.method, CONN_ESTABL].concat())
-        .await?;
-    split_hello_phrase(&mut socket, &mut server_con, fdpi_methods).await?;
-    copy_bidirectional_with_sizes(&mut server_con, &mut socket, 128, 128).await?;
-    log::info!("socket close: {}", addr.domain);
+async fn process_socks(socket: &mut TcpStream, ctx: &Ctx) -> Result<()> {
+    let req = socks::handshake(socket, ctx.socks_auth.as_ref()).await?;
+    let target = Target {
+        domain: &req.domain,
+        ip: req.ip,
+        port: req.port,
+        source: None,
+    };
+    let mut server_con = match connect_target(ctx, &target, &first_strategy(ctx, &target)).await {
+        Ok(s) => s,
+        Err(e) => {
+            let rep = e.downcast_ref::<ProxyError>().map_or(socks::REP_FAIL, |pe| {
+                log::info!("{}", pe);
+                pe.socks_reply()
+            });
+            socks::reply(socket, req.version, rep, None).await?;
+            return Err(e);
+        }
+    };
+    socks::reply(socket, req.version, socks::REP_OK, server_con.local_addr().ok()).await?;
+    tunnel(socket, &mut server_con, BytesMut::new(), ctx, &target).await?;
+    log::info!("socket close: {}", req.domain);
+
+    Ok(())
+}
+
+/// The destination is known from the socket, the name - from SNI or the Host header.
+/// source - connect to the server from this (client) address
+async fn process_transparent(
+    socket: &mut TcpStream,
+    ctx: &Ctx,
+    dst: SocketAddr,
+    source: Option<IpAddr>,
+) -> Result<()> {
+    let dst = SocketAddr::new(dst.ip().to_canonical(), dst.port());
+    let mut hello = BytesMut::with_capacity(2048);
+    tls::read_client_hello(socket, &mut hello, ctx.hello_timeout).await?;
+    let domain = tls::sni(&hello)
+        .or_else(|| http::find_host(&hello))
+        .unwrap_or_else(|| dst.ip().to_string());
+    log::info!("transparent: {} -> {}", domain, dst);
+
+    let target = Target {
+        domain: &domain,
+        ip: Some(dst.ip()),
+        port: dst.port(),
+        source: source.map(|ip| ip.to_canonical()),
+    };
+    let mut server_con = connect_target(ctx, &target, &first_strategy(ctx, &target)).await?;
+    tunnel(socket, &mut server_con, hello, ctx, &target).await?;
+    log::info!("socket close: {}", domain);
 
     Ok(())
 }
 
-fn parse_http_head(input: &[u8]) -> Result<HttpHead> {
-    let mut r: HttpHead = Default::default();
-    let first_string = input.split(|x| *x == b'\r').next().ok_or("err")?;
-    log::info!("http head: {:?}", String::from_utf8_lossy(first_string));
-    let mut it = first_string.split(|x| *x == b' ');
-    r.command = it.next().ok_or("err")?;
-    if r.command != b"CONNECT" {
-        return Err("err command".into());
-    }
-    r.domain = std::str::from_utf8(it.next().ok_or("err")?)?;
-    let addr_port = r.domain.split_once(':').unwrap_or((r.domain, "443"));
-    (r.domain, r.port) = (addr_port.0, addr_port.1.parse::<u16>()?);
-    r.method = it.next().ok_or("err")?;
-    log::trace!("end_http head: {:?}", r);
-    Ok(r)
+/// The strategy of the first connection to the target, the same tunnel picks:
+/// its socket options are set before connect
+fn first_strategy(ctx: &Ctx, t: &Target<'_>) -> Strategy {
+    match ctx.rules.find(t.domain, t.port) {
+        Some(Action::Strategy(_, s)) => s.clone(),
+        Some(_) => Strategy::default(),
+        None => match &ctx.auto {
+            Some(auto) => auto
+                .get(t.domain)
+                .or_else(|| auto.candidates.first().cloned())
+                .unwrap_or_default(),
+            None => ctx.strategy.clone(),
+        },
+    }
 }
 
+/// dns (unless ip literal), policy checks and connect to the first reachable address,
+/// from the source address if set, with the socket options of the strategy
+async fn connect_target(ctx: &Ctx, t: &Target<'_>, strategy: &Strategy) -> Result<TcpStream> {
+    let (domain, port) = (t.domain, t.port);
+    // blocked before the lookup: a blocked name that does not resolve is still a 403
+    if let Some(Action::Block) = ctx.rules.find(domain, port) {
+        return Err(ProxyError::Denied(format!("rule: {}", domain)).into());
+    }
+    let ips = match t.ip {
+        Some(ip) => vec![ip],
+        None => ctx.dns.resolve(domain).await,
+    };
+    if ips.is_empty() {
+        log::error!("not resolve dns to ip");
+        return Err(ProxyError::Dns(domain.to_string()).into());
+    }
 
-async fn split_hello_phrase(reader: &mut TcpStream, writer: &mut TcpStream, fdpi_methods: (Vec<u8>, Vec<u8>, u8, bool)) -> Result<()> {
-    let mut hello_buf = [0; 516];
-    let _ = reader.read(&mut hello_buf).await?;
-    let ttl = writer.ttl()?;       
-    writer.set_nodelay(true)?;
-    let mut parts:Vec<&[u8]> = Vec::new();
-    
-    log::debug!("[hello] {:?}", &hello_buf);
+    if ips.iter().any(|ip| ip.is_loopback()) {
+        return Err(ProxyError::Denied("loopback".into()).into());
+    }
 
-    let mut p1_:usize = 0;
-    let mut enable_sni = false; 
-    if let Some((p1, p2)) = take_sni_point(&hello_buf) {
-        p1_ = p1;
-        if fdpi_methods.3 {
-            hello_buf[p1]-=32;
-            hello_buf[p2-1]-=32;
-            hello_buf[p1+4]-=32+2;
-        }
-        
-        log::info!("[sni] {:?}", String::from_utf8_lossy(&hello_buf[p1..p2]));
-        enable_sni = true;
-    }          
-
-    let mut buf = &hello_buf[..];
-    let mut part: &[u8];
-    for i in fdpi_methods.0 {
-        (buf,part) = split_at_revers(buf,i as usize);
-        parts.push(part);
-    }
-     
-    if enable_sni {
-        (buf,part) =  split_at_revers(buf, buf.len()-(hello_buf.len()-p1_));
-        parts.push(part);
-        for i in fdpi_methods.1 {
-            (buf,part) = split_at_revers(buf,i as usize);
-            parts.push(part);
+    let addrs = connect::sort_addrs(&ips, ctx.dns.preferred(domain), port);
+    let strategy = strategy.clone();
+    let setup = move |s: &TcpSocket| strategy.connect(s);
+    let server_con = connect::happy_eyeballs(addrs, t.source, ctx.connect_timeout, setup)
+        .await
+        .map_err(ProxyError::from)?;
+    if t.ip.is_none() {
+        ctx.dns.remember(domain, server_con.peer_addr()?.ip());
+    }
+    Ok(server_con)
+}
+
+/// desync the ClientHello by the domain rule, then relay
+async fn tunnel(
+    socket: &mut TcpStream,
+    server_con: &mut TcpStream,
+    hello: BytesMut,
+    ctx: &Ctx,
+    t: &Target<'_>,
+) -> Result<()> {
+    let (domain, port) = (t.domain, t.port);
+    let direct = Strategy::default();
+    let rule = ctx.rules.find(domain, port);
+    if let Some(a) = rule {
+        log::info!("[rule] {}:{} -> {}", domain, port, a);
+    }
+    let strategy = match rule {
+        Some(Action::Strategy(_, s)) => s,
+        Some(Action::Direct) => &direct,
+        Some(Action::Block) => return Err(ProxyError::Denied(format!("rule: {}", domain)).into()),
+        None => match &ctx.auto {
+            Some(auto) => return auto_tunnel(socket, server_con, hello, ctx, auto, t).await,
+            None => &ctx.strategy,
+        },
+    };
+    if ctx.fallback.is_empty() {
+        split_hello_phrase(socket, server_con, hello, ctx.hello_timeout, strategy).await?;
+        copy_bidirectional_with_sizes(server_con, socket, 128, 128).await?;
+        return Ok(());
+    }
+
+    // the hello is kept to send it again with a fallback
+    let hello = read_hello(socket, hello, ctx.hello_timeout).await?;
+    if hello.is_empty() {
+        strategy.done(server_con)?;
+    } else {
+        let strategies: Vec<&Strategy> = std::iter::once(strategy).chain(&ctx.fallback).collect();
+        let timeout = ctx.retry_timeout;
+        match try_strategies(socket, server_con, &hello, ctx, t, &strategies, timeout).await? {
+            Some(0) => {}
+            Some(n) => log::info!("[retry] {}: {}", domain, strategies[n]),
+            None => return Err(format!("retry: no strategy works for {}", domain).into()),
         }
     }
+    copy_bidirectional_with_sizes(server_con, socket, 128, 128).await?;
+    Ok(())
+}
 
-    let mut flag = true;
-    for i in parts {
-        if flag { writer.set_ttl(fdpi_methods.2 as u32)?; } else { writer.set_ttl(ttl); }
-        flag = !flag;
-        writer.write(i).await?;
+/// The cached strategy, then the candidates until the server answers with a ServerHello
+async fn auto_tunnel(
+    socket: &mut TcpStream,
+    server_con: &mut TcpStream,
+    hello: BytesMut,
+    ctx: &Ctx,
+    auto: &Auto,
+    t: &Target<'_>,
+) -> Result<()> {
+    let cached = auto.get(t.domain);
+    let strategies: Vec<&Strategy> = cached.iter().chain(&auto.candidates).collect();
+    let hello = read_hello(socket, hello, ctx.hello_timeout).await?;
+    if hello.first() != Some(&tls::HANDSHAKE) {
+        // not TLS, nothing to wait for; the connection was made for the first strategy
+        if let Some(s) = strategies.first() {
+            s.done(server_con)?;
+        }
+        server_con.write_all(&hello).await?;
+        copy_bidirectional_with_sizes(server_con, socket, 128, 128).await?;
+        return Ok(());
     }
-                
-    writer.write(buf).await?;
-    writer.set_nodelay(false)?;
-    writer.set_ttl(ttl); 
 
+    let winner = try_strategies(socket, server_con, &hello, ctx, t, &strategies, auto.timeout).await?;
+    if cached.is_some() && winner != Some(0) {
+        auto.failed(t.domain);
+    }
+    let Some(n) = winner else {
+        log::info!("[auto] {}: no strategy works", t.domain);
+        return Err(format!("auto: no strategy works for {}", t.domain).into());
+    };
+    if n > 0 || cached.is_none() {
+        log::info!("[auto] {}: {}", t.domain, strategies[n]);
+    }
+    auto.remember(t.domain, strategies[n]);
+    copy_bidirectional_with_sizes(server_con, socket, 128, 128).await?;
     Ok(())
 }
 
+/// Sends the hello with every strategy in turn, a new connection for every next try,
+/// until the server answers. Nothing reaches the client before the answer.
+/// Returns the index of the strategy that worked, its answer is relayed.
+async fn try_strategies(
+    socket: &mut TcpStream,
+    server_con: &mut TcpStream,
+    hello: &[u8],
+    ctx: &Ctx,
+    t: &Target<'_>,
+    strategies: &[&Strategy],
+    timeout: Duration,
+) -> Result<Option<usize>> {
+    let tls = hello.first() == Some(&tls::HANDSHAKE);
+    for (n, strategy) in strategies.iter().enumerate() {
+        if n > 0 {
+            log::debug!("[retry] {}: {}", t.domain, strategy);
+            match connect_target(ctx, t, strategy).await {
+                Ok(con) => *server_con = con,
+                Err(e) => {
+                    log::debug!("[retry] {}: connect: {}", t.domain, e);
+                    continue;
+                }
+            }
+        }
+        let answer = match strategy.send(server_con, hello.to_vec()).await {
+            Ok(()) => auto::probe(server_con, timeout, tls).await,
+            Err(e) => {
+                log::debug!("[retry] send: {}", e);
+                None
+            }
+        };
+        if let Some(answer) = answer {
+            socket.write_all(&answer).await?;
+            return Ok(Some(n));
+        }
+        log::debug!("[retry] {}: {} failed", t.domain, strategy);
+    }
+    Ok(None)
+}
+
+async fn split_hello_phrase(
+    reader: &mut TcpStream,
+    writer: &mut TcpStream,
+    hello: BytesMut,
+    hello_timeout: Duration,
+    strategy: &Strategy,
+) -> Result<()> {
+    let hello_buf = read_hello(reader, hello, hello_timeout).await?;
+    if hello_buf.is_empty() {
+        // nothing to send, the socket options still go back before the relay
+        strategy.done(writer)?;
+        return Ok(());
+    }
+    strategy.send(writer, hello_buf).await?;
+
+    Ok(())
+}
 
+/// The complete ClientHello, records joined
+async fn read_hello(reader: &mut TcpStream, mut hello: BytesMut, hello_timeout: Duration) -> Result<Vec<u8>> {
+    tls::read_client_hello(reader, &mut hello, hello_timeout).await?;
+    let hello_buf = tls::join_records(&hello);
+    log::debug!("[hello] {:?}", &hello_buf);
+    if let Some((p1, p2)) = take_sni_point(&hello_buf) {
+        log::info!("[sni] {:?}", String::from_utf8_lossy(&hello_buf[p1..p2]));
+    }
+    Ok(hello_buf)
+}
@@ -0,0 +1,98 @@
+use crate::rng::Rng;
+use std::str::FromStr;
+
+/// Server name case change, only ASCII letters are touched:
+/// digits, dots and hyphens stay as they are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Case {
+    /// every letter random case
+    Random,
+    /// "ExAmPlE.CoM"
+    Alternate,
+    /// "Example.coM"
+    FirstLast,
+    /// "EXAMPLE.COM"
+    Upper,
+}
+
+impl Case {
+    pub fn apply(self, name: &mut [u8], rng: &Rng) {
+        let last = name.len().saturating_sub(1);
+        for (i, b) in name.iter_mut().enumerate() {
+            let upper = match self {
+                Self::Random => Some(rng.range(0, 1) == 1),
+                Self::Alternate => Some(i % 2 == 0),
+                Self::FirstLast => (i == 0 || i == last).then_some(true),
+                Self::Upper => Some(true),
+            };
+            match upper {
+                Some(true) => b.make_ascii_uppercase(),
+                Some(false) => b.make_ascii_lowercase(),
+                None => {}
+            }
+        }
+    }
+}
+
+impl FromStr for Case {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Self::Random),
+            "alternate" => Ok(Self::Alternate),
+            "firstlast" => Ok(Self::FirstLast),
+            "upper" => Ok(Self::Upper),
+            _ => Err(format!("unknown case mode: {} (random, alternate, firstlast, upper)", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &[u8] = b"xn--80ak6aa92e.my-site42.example.com";
+
+    fn apply(case: Case) -> Vec<u8> {
+        let mut name = NAME.to_vec();
+        case.apply(&mut name, &Rng::new());
+        assert_eq!(name.len(), NAME.len());
+        for (a, b) in name.iter().zip(NAME) {
+            if b.is_ascii_alphabetic() {
+                assert!(a.eq_ignore_ascii_case(b));
+            } else {
+                assert_eq!(a, b, "{} changed", *b as char);
+            }
+        }
+        name
+    }
+
+    #[test]
+    fn random() {
+        for _ in 0..20 {
+            apply(Case::Random);
+        }
+    }
+
+    #[test]
+    fn alternate() {
+        let mut name = b"ab-cd.1e".to_vec();
+        Case::Alternate.apply(&mut name, &Rng::new());
+        assert_eq!(name, b"Ab-cD.1e");
+        apply(Case::Alternate);
+    }
+
+    #[test]
+    fn first_last() {
+        assert_eq!(apply(Case::FirstLast), b"Xn--80ak6aa92e.my-site42.example.coM");
+        let mut name = b"1.example.com-".to_vec();
+        Case::FirstLast.apply(&mut name, &Rng::new());
+        assert_eq!(name, b"1.example.com-");
+    }
+
+    #[test]
+    fn upper() {
+        assert_eq!(apply(Case::Upper), NAME.to_ascii_uppercase());
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{http, rng::Rng, tls};
+use std::str::FromStr;
+use take_sni::take_sni_point;
+
+/// Where a position is counted from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Base {
+    Start,
+    End,
+    Sni,
+    SniEnd,
+    SniMid,
+    Record,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Offset {
+    Fixed(isize),
+    /// inclusive range, a new value per evaluation
+    Rand(isize, isize),
+}
+
+/// Split position: `[base][+-offset]`, offset - a number or `rand(a,b)`.
+/// base: sni (host), sni_end (host_end), sni_mid (host_mid) - the server name in TLS,
+/// the Host value in http; record - the record payload start.
+/// Without a base counts from the start, a negative one from the end.
+/// Parsed once, evaluated against every hello.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pos {
+    base: Base,
+    offset: Offset,
+}
+
+/// The hello points positions are evaluated against
+#[derive(Debug)]
+pub struct Layout {
+    pub len: usize,
+    /// server name (TLS) or Host value (http)
+    pub host: Option<(usize, usize)>,
+    pub record: Option<usize>,
+}
+
+impl Layout {
+    pub fn new(buf: &[u8]) -> Self {
+        let tls = buf.first() == Some(&tls::HANDSHAKE);
+        Self {
+            len: buf.len(),
+            host: if tls { take_sni_point(buf) } else { http::host_point(buf) },
+            record: tls.then_some(tls::RECORD_HEADER),
+        }
+    }
+
+    /// n bytes were inserted at pos
+    pub fn insert(&mut self, pos: usize, n: usize) {
+        let shift = |p: &mut usize| {
+            if *p >= pos {
+                *p += n;
+            }
+        };
+        self.len += n;
+        if let Some((p1, p2)) = self.host.as_mut() {
+            shift(p1);
+            shift(p2);
+        }
+        if let Some(r) = self.record.as_mut() {
+            shift(r);
+        }
+    }
+}
+
+impl Pos {
+    /// position inside the buffer (0 < pos < len), None - no such point in this hello
+    pub fn eval(&self, l: &Layout, rng: &Rng) -> Option<usize> {
+        let base = match self.base {
+            Base::Start => 0,
+            Base::End => l.len,
+            Base::Sni => l.host?.0,
+            Base::SniEnd => l.host?.1,
+            Base::SniMid => {
+                let (p1, p2) = l.host?;
+                p1 + (p2 - p1) / 2
+            }
+            Base::Record => l.record?,
+        };
+        let offset = match self.offset {
+            Offset::Fixed(n) => n,
+            Offset::Rand(a, b) => {
+                let n = rng.range(a, b);
+                log::debug!("[rand] offset {} of {}..={}", n, a, b);
+                n
+            }
+        };
+        base.checked_add_signed(offset).filter(|p| *p > 0 && *p < l.len)
+    }
+}
+
+impl FromStr for Pos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let name_len = if s.starts_with("rand(") {
+            0
+        } else {
+            s.find(|c: char| !(c.is_ascii_lowercase() || c == '_')).unwrap_or(s.len())
+        };
+        let (name, rest) = s.split_at(name_len);
+        let base = match name {
+            "" if rest.starts_with('-') => Base::End,
+            "" => Base::Start,
+            "sni" | "host" => Base::Sni,
+            "sni_end" | "host_end" => Base::SniEnd,
+            "sni_mid" | "host_mid" => Base::SniMid,
+            "record" => Base::Record,
+            _ => return Err(format!("unknown position base: {}", name)),
+        };
+        let offset = match rest.as_bytes().first() {
+            None => Offset::Fixed(0),
+            Some(b'+') => parse_offset(&rest[1..])?,
+            Some(b'-') => match parse_offset(&rest[1..])? {
+                Offset::Fixed(n) => Offset::Fixed(-n),
+                Offset::Rand(a, b) => Offset::Rand(-b, -a),
+            },
+            _ if name.is_empty() => parse_offset(rest)?,
+            _ => return Err(format!("bad position: {}", s)),
+        };
+        Ok(Self { base, offset })
+    }
+}
+
+fn parse_offset(s: &str) -> Result<Offset, String> {
+    let num = |n: &str| n.trim().parse::<isize>().map_err(|e| format!("{}: {}", n, e));
+    match s.strip_prefix("rand(").and_then(|r| r.strip_suffix(')')) {
+        Some(r) => {
+            let (a, b) = r.split_once(',').ok_or_else(|| format!("rand(a,b) expected: {}", s))?;
+            let (a, b) = (num(a)?, num(b)?);
+            Ok(Offset::Rand(a.min(b), a.max(b)))
+        }
+        None => num(s).map(Offset::Fixed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Layout {
+        Layout { len: 100, host: Some((40, 51)), record: Some(5) }
+    }
+
+    #[test]
+    fn parse() {
+        let p: Pos = "sni_end-2".parse().unwrap();
+        assert_eq!(p, Pos { base: Base::SniEnd, offset: Offset::Fixed(-2) });
+        let p: Pos = "-10".parse().unwrap();
+        assert_eq!(p, Pos { base: Base::End, offset: Offset::Fixed(-10) });
+        let p: Pos = "rand(1,8)".parse().unwrap();
+        assert_eq!(p, Pos { base: Base::Start, offset: Offset::Rand(1, 8) });
+        let p: Pos = "sni+rand(8,1)".parse().unwrap();
+        assert_eq!(p, Pos { base: Base::Sni, offset: Offset::Rand(1, 8) });
+        assert!("foo+1".parse::<Pos>().is_err());
+        assert!("sni*2".parse::<Pos>().is_err());
+    }
+
+    #[test]
+    fn eval() {
+        let (l, rng) = (layout(), Rng::new());
+        let eval = |s: &str| s.parse::<Pos>().unwrap().eval(&l, &rng);
+        assert_eq!(eval("sni_end-2"), Some(49));
+        assert_eq!(eval("-10"), Some(90));
+        assert_eq!(eval("sni_mid"), Some(45));
+        assert_eq!(eval("record+1"), Some(6));
+        // outside the hello
+        assert_eq!(eval("0"), None);
+        assert_eq!(eval("-100"), None);
+        for _ in 0..100 {
+            assert!((1..=8).contains(&eval("rand(1,8)").unwrap()));
+        }
+    }
+}
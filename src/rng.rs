@@ -0,0 +1,99 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+/// --seed: the n-th hello gets the same values on every run
+static SEED: OnceLock<u64> = OnceLock::new();
+static HELLOS: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_seed(seed: u64) {
+    let _ = SEED.set(seed);
+}
+
+/// xorshift state of one hello, every random value of it comes from here.
+/// Atomic only to be shared by the steps of the hello, not between hellos.
+#[derive(Debug)]
+pub struct Rng(AtomicU64);
+
+impl Rng {
+    /// from --seed and the hello number, random without --seed
+    pub fn new() -> Self {
+        let x = match SEED.get() {
+            Some(seed) => {
+                let n = HELLOS.fetch_add(1, Ordering::Relaxed);
+                // splitmix64, so that close numbers give unrelated states
+                let mut x = seed.wrapping_add(n.wrapping_mul(0x9e3779b97f4a7c15));
+                x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+                x ^ (x >> 31)
+            }
+            None => RandomState::new().build_hasher().finish(),
+        };
+        Self(AtomicU64::new(x | 1))
+    }
+
+    fn next(&self) -> u64 {
+        let mut x = self.0.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0.store(x, Ordering::Relaxed);
+        x
+    }
+
+    /// lo..=hi
+    pub fn range(&self, lo: isize, hi: isize) -> isize {
+        match (hi.abs_diff(lo) as u64).checked_add(1) {
+            Some(span) => lo.wrapping_add((self.next() % span) as isize),
+            None => self.next() as isize,
+        }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `N` or `rand(a,b)`: a..=b, new for every connection, up to u32::MAX
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range(pub u64, pub u64);
+
+impl Range {
+    pub fn get(&self, rng: &Rng) -> u64 {
+        rng.range(self.0 as isize, self.1 as isize) as u64
+    }
+}
+
+impl FromStr for Range {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let num = |n: &str| n.trim().parse::<u32>().map(u64::from).map_err(|e| format!("{}: {}", s, e));
+        match s.strip_prefix("rand(").and_then(|r| r.strip_suffix(')')) {
+            Some(r) => {
+                let (a, b) = r.split_once(',').ok_or_else(|| format!("rand(a,b) expected: {}", s))?;
+                let (a, b) = (num(a)?, num(b)?);
+                Ok(Self(a.min(b), a.max(b)))
+            }
+            None => num(s).map(|n| Self(n, n)),
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 == self.1 {
+            true => write!(f, "{}", self.0),
+            false => write!(f, "rand({},{})", self.0, self.1),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{desync::Strategy, Result};
+use regex::Regex;
+use std::{collections::HashMap, fmt, path::Path};
+
+/// What to do with a connection
+#[derive(Debug)]
+pub enum Action {
+    /// no desync
+    Direct,
+    Block,
+    Strategy(String, Strategy),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct => write!(f, "direct"),
+            Self::Block => write!(f, "block"),
+            Self::Strategy(name, _) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Pattern {
+    /// `*`
+    Any,
+    Exact(String),
+    /// `*.example.com` - example.com and its subdomains
+    Suffix(String),
+    /// `~regex`
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "*" => Self::Any,
+            _ if s.starts_with('~') => Self::Regex(Regex::new(&s[1..])?),
+            _ => match s.strip_prefix("*.") {
+                Some(d) => Self::Suffix(d.to_ascii_lowercase()),
+                None => Self::Exact(s.to_ascii_lowercase()),
+            },
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exact(d) => domain.eq_ignore_ascii_case(d),
+            Self::Suffix(d) => {
+                let domain = domain.to_ascii_lowercase();
+                domain == *d || domain.strip_suffix(d.as_str()).is_some_and(|s| s.ends_with('.'))
+            }
+            Self::Regex(r) => r.is_match(domain),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Pattern,
+    /// empty - any port
+    ports: Vec<u16>,
+    action: Action,
+}
+
+/// Domain rules, first match wins, `*` as the last rule is the default.
+/// Without a matching rule the global strategy is used.
+///
+/// ```text
+/// strategy yt split:sni+1,disorder
+/// # pattern [port,port] action: direct, block, a strategy name or a strategy
+/// *.googlevideo.com 443 yt
+/// ~^(www\.)?example\.(com|org)$ direct
+/// ads.example.net block
+/// * split:sni,disorder
+/// ```
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut named: HashMap<String, Strategy> = HashMap::new();
+        let mut rules = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: String| format!("line {}: {}", n + 1, e);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let ["strategy", name, spec] = fields[..] {
+                named.insert(name.to_string(), spec.parse().map_err(err)?);
+                continue;
+            }
+            let (pattern, ports, action) = match fields[..] {
+                [p, a] => (p, None, a),
+                [p, ports, a] => (p, Some(ports), a),
+                _ => return Err(err("pattern [ports] action expected".into()).into()),
+            };
+            let ports = match ports {
+                Some(p) => p
+                    .split(',')
+                    .map(|p| p.parse::<u16>().map_err(|e| err(format!("port {}: {}", p, e))))
+                    .collect::<std::result::Result<_, _>>()?,
+                None => Vec::new(),
+            };
+            let action = match action {
+                "direct" => Action::Direct,
+                "block" => Action::Block,
+                name => match named.get(name) {
+                    Some(s) => Action::Strategy(name.to_string(), s.clone()),
+                    None => Action::Strategy(name.to_string(), name.parse().map_err(err)?),
+                },
+            };
+            let pattern = Pattern::parse(pattern).map_err(|e| err(e.to_string()))?;
+            rules.push(Rule { pattern, ports, action });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn find(&self, domain: &str, port: u16) -> Option<&Action> {
+        self.rules
+            .iter()
+            .find(|r| (r.ports.is_empty() || r.ports.contains(&port)) && r.pattern.matches(domain))
+            .map(|r| &r.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(rules: &Rules, domain: &str, port: u16) -> Option<String> {
+        rules.find(domain, port).map(|a| a.to_string())
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = Rules::parse(
+            "strategy yt split:sni+1,disorder\n\
+             # comment\n\
+             *.googlevideo.com 443 yt\n\
+             ads.example.net block\n\
+             ~^(www\\.)?example\\.org$ direct\n\
+             *.example.net direct\n\
+             * split:sni\n",
+        )
+        .unwrap();
+        assert_eq!(action(&rules, "rr1.googlevideo.com", 443).as_deref(), Some("yt"));
+        assert_eq!(action(&rules, "googlevideo.com", 443).as_deref(), Some("yt"));
+        // the port does not match, the default
+        assert_eq!(action(&rules, "rr1.googlevideo.com", 80).as_deref(), Some("split:sni"));
+        // block goes before the wider direct
+        assert_eq!(action(&rules, "ADS.example.net", 443).as_deref(), Some("block"));
+        assert_eq!(action(&rules, "cdn.example.net", 443).as_deref(), Some("direct"));
+        assert_eq!(action(&rules, "www.example.org", 443).as_deref(), Some("direct"));
+        assert_eq!(action(&rules, "notexample.net", 443).as_deref(), Some("split:sni"));
+    }
+
+    #[test]
+    fn no_default() {
+        let rules = Rules::parse("example.com block").unwrap();
+        assert!(rules.find("example.org", 443).is_none());
+        assert!(Rules::parse("example.com 443 80 block").is_err());
+        assert!(Rules::parse("example.com nosuchstep").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{http::parse_host, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SOCKS4: u8 = 4;
+pub const SOCKS5: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const NO_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+/// SOCKS5 REP codes, for SOCKS4 anything but REP_OK is "rejected"
+pub const REP_OK: u8 = 0;
+pub const REP_FAIL: u8 = 1;
+const REP_CMD: u8 = 7;
+const REP_ATYP: u8 = 8;
+const SOCKS4_GRANTED: u8 = 90;
+const SOCKS4_REJECTED: u8 = 91;
+/// max USERID / hostname length in a SOCKS4 request
+const SOCKS4_STR_CAP: usize = 255;
+
+#[derive(Debug)]
+pub struct Request {
+    pub version: u8,
+    pub domain: String,
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+}
+
+/// SOCKS4/4a or SOCKS5 handshake up to the CONNECT request.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    s: &mut S,
+    auth: Option<&(String, String)>,
+) -> Result<Request> {
+    let version = s.read_u8().await?;
+    let (domain, ip, port) = match version {
+        SOCKS4 => socks4_handshake(s, auth.is_some()).await?,
+        SOCKS5 => socks5_handshake(s, auth).await?,
+        _ => return Err("err socks version".into()),
+    };
+    log::info!("socks{} connect: {}:{}", version, domain, port);
+    Ok(Request { version, domain, ip, port })
+}
+
+/// SOCKS4 CONNECT, SOCKS4a when DSTIP is 0.0.0.x - the host name follows USERID
+/// and is resolved by us. There is no password in SOCKS4, so it is refused when auth is set.
+async fn socks4_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    s: &mut S,
+    auth: bool,
+) -> Result<(String, Option<IpAddr>, u16)> {
+    let mut req = [0u8; 7];
+    s.read_exact(&mut req).await?;
+    let port = u16::from_be_bytes([req[1], req[2]]);
+    let ip = Ipv4Addr::new(req[3], req[4], req[5], req[6]);
+    let _user_id = read_cstr(s).await?;
+    let host = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => Some(read_cstr(s).await?),
+        _ => None,
+    };
+
+    if auth {
+        reply(s, SOCKS4, REP_FAIL, None).await?;
+        return Err("socks4: auth required".into());
+    }
+    if req[0] != CMD_CONNECT {
+        reply(s, SOCKS4, REP_CMD, None).await?;
+        return Err("socks4: command not supported".into());
+    }
+    match host {
+        Some(host) => {
+            let (domain, ip) = match std::str::from_utf8(&host).map(parse_host) {
+                Ok(Ok(r)) => r,
+                _ => {
+                    reply(s, SOCKS4, REP_FAIL, None).await?;
+                    return Err("socks4a: err host".into());
+                }
+            };
+            Ok((domain, ip, port))
+        }
+        None => Ok((ip.to_string(), Some(ip.into()), port)),
+    }
+}
+
+async fn read_cstr<S: AsyncRead + Unpin>(s: &mut S) -> Result<Vec<u8>> {
+    let mut r = Vec::new();
+    loop {
+        match s.read_u8().await? {
+            0 => return Ok(r),
+            _ if r.len() >= SOCKS4_STR_CAP => return Err("socks4: string too long".into()),
+            b => r.push(b),
+        }
+    }
+}
+
+/// SOCKS5 greeting, username/password auth (RFC 1929) if set and the CONNECT request.
+async fn socks5_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    s: &mut S,
+    auth: Option<&(String, String)>,
+) -> Result<(String, Option<IpAddr>, u16)> {
+    let mut methods = vec![0u8; s.read_u8().await? as usize];
+    s.read_exact(&mut methods).await?;
+    let method = if auth.is_some() { USER_PASS } else { NO_AUTH };
+    if !methods.contains(&method) {
+        s.write_all(&[SOCKS5, NO_METHOD]).await?;
+        return Err("socks: no acceptable auth method".into());
+    }
+    s.write_all(&[SOCKS5, method]).await?;
+
+    if let Some((user, pass)) = auth {
+        let mut ver_len = [0u8; 2];
+        s.read_exact(&mut ver_len).await?;
+        let mut uname = vec![0u8; ver_len[1] as usize];
+        s.read_exact(&mut uname).await?;
+        let mut passwd = vec![0u8; s.read_u8().await? as usize];
+        s.read_exact(&mut passwd).await?;
+        let ok = uname == user.as_bytes() && passwd == pass.as_bytes();
+        s.write_all(&[1, if ok { 0 } else { 1 }]).await?;
+        if !ok {
+            return Err("socks: auth failed".into());
+        }
+    }
+
+    let mut req = [0u8; 4];
+    s.read_exact(&mut req).await?;
+    let (domain, ip) = match req[3] {
+        ATYP_V4 => {
+            let mut a = [0u8; 4];
+            s.read_exact(&mut a).await?;
+            let ip = IpAddr::from(a);
+            (ip.to_string(), Some(ip))
+        }
+        ATYP_V6 => {
+            let mut a = [0u8; 16];
+            s.read_exact(&mut a).await?;
+            let ip = IpAddr::from(a);
+            (ip.to_string(), Some(ip))
+        }
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; s.read_u8().await? as usize];
+            s.read_exact(&mut name).await?;
+            match std::str::from_utf8(&name).map(parse_host) {
+                Ok(Ok(r)) => r,
+                _ => {
+                    reply(s, SOCKS5, REP_FAIL, None).await?;
+                    return Err("socks: err host".into());
+                }
+            }
+        }
+        _ => {
+            reply(s, SOCKS5, REP_ATYP, None).await?;
+            return Err("socks: address type not supported".into());
+        }
+    };
+    let port = s.read_u16().await?;
+    if req[1] != CMD_CONNECT {
+        reply(s, SOCKS5, REP_CMD, None).await?;
+        return Err("socks: command not supported".into());
+    }
+    Ok((domain, ip, port))
+}
+
+pub async fn reply<S: AsyncWrite + Unpin>(
+    s: &mut S,
+    version: u8,
+    rep: u8,
+    bound: Option<SocketAddr>,
+) -> Result<()> {
+    let r = match (version, bound) {
+        (SOCKS4, bound) => {
+            let (port, ip) = match bound {
+                Some(SocketAddr::V4(a)) => (a.port(), a.ip().octets()),
+                _ => (0, [0; 4]),
+            };
+            let cd = if rep == REP_OK { SOCKS4_GRANTED } else { SOCKS4_REJECTED };
+            [&[0, cd][..], &port.to_be_bytes(), &ip].concat()
+        }
+        (_, Some(SocketAddr::V6(a))) => {
+            [&[SOCKS5, rep, 0, ATYP_V6][..], &a.ip().octets(), &a.port().to_be_bytes()].concat()
+        }
+        (_, Some(SocketAddr::V4(a))) => {
+            [&[SOCKS5, rep, 0, ATYP_V4][..], &a.ip().octets(), &a.port().to_be_bytes()].concat()
+        }
+        (_, None) => vec![SOCKS5, rep, 0, ATYP_V4, 0, 0, 0, 0, 0, 0],
+    };
+    s.write_all(&r).await?;
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use socket2::SockRef;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+use tokio::{
+    io::Interest,
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+
+/// TTL for IPv4, hop limit (IPV6_UNICAST_HOPS) for IPv6
+pub fn ttl(s: &TcpStream) -> io::Result<u32> {
+    let sock = SockRef::from(s);
+    if s.local_addr()?.is_ipv6() {
+        sock.unicast_hops_v6()
+    } else {
+        sock.ttl()
+    }
+}
+
+pub fn set_ttl(s: &TcpStream, ttl: u32) -> io::Result<()> {
+    let sock = SockRef::from(s);
+    if s.local_addr()?.is_ipv6() {
+        sock.set_unicast_hops_v6(ttl)
+    } else {
+        sock.set_ttl(ttl)
+    }
+}
+
+/// TCP_MAXSEG, before connect: the clamp is fixed by the handshake
+pub fn set_mss(s: &TcpSocket, mss: u32) -> io::Result<()> {
+    SockRef::from(s).set_mss(mss)
+}
+
+/// SO_BUF_LOCK (asm-generic value), not in libc yet
+#[cfg(target_os = "linux")]
+const SO_BUF_LOCK: libc::c_int = 72;
+
+/// Back to the default SO_RCVBUF and receive buffer autotuning after a set_recv_buffer_size:
+/// setting SO_RCVBUF locks the size, SO_BUF_LOCK (linux 5.14+) unlocks it
+#[cfg(target_os = "linux")]
+pub fn reset_recv_buffer(s: &TcpStream) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // the kernel doubles the value set, the default is read doubled
+    SockRef::from(s).set_recv_buffer_size(default_recv_buffer() as usize / 2)?;
+    let unlock: libc::c_int = 0;
+    let r = unsafe {
+        libc::setsockopt(
+            s.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_BUF_LOCK,
+            &unlock as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        log::debug!("[window] autotuning stays off: {}", io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reset_recv_buffer(s: &TcpStream) -> io::Result<()> {
+    SockRef::from(s).set_recv_buffer_size(default_recv_buffer() as usize / 2)
+}
+
+/// SO_RCVBUF of a new socket (tcp_rmem default)
+fn default_recv_buffer() -> u32 {
+    static SIZE: OnceLock<u32> = OnceLock::new();
+    *SIZE.get_or_init(|| {
+        TcpSocket::new_v4()
+            .and_then(|s| s.recv_buffer_size())
+            .unwrap_or(128 * 1024)
+    })
+}
+
+/// Destination before iptables REDIRECT (SO_ORIGINAL_DST / IP6T_SO_ORIGINAL_DST)
+#[cfg(target_os = "linux")]
+pub fn original_dst(s: &TcpStream) -> io::Result<SocketAddr> {
+    let sock = SockRef::from(s);
+    let addr = match s.local_addr()? {
+        SocketAddr::V6(a) if a.ip().to_ipv4_mapped().is_none() => sock.original_dst_ipv6()?,
+        _ => sock.original_dst()?,
+    };
+    addr.as_socket()
+        .ok_or_else(|| io::Error::other("original dst is not inet"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Listener for TPROXY: IP_TRANSPARENT accepts connections to foreign addresses,
+/// the local address of an accepted socket is the original destination
+#[cfg(target_os = "linux")]
+pub fn transparent_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let sock = new_socket(addr)?;
+    sock.set_reuseaddr(true)?;
+    SockRef::from(&sock).set_ip_transparent(true)?;
+    sock.bind(addr)?;
+    sock.listen(1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn transparent_listener(_: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Upstream socket bound to a foreign (client) address, IP_TRANSPARENT
+#[cfg(target_os = "linux")]
+pub fn transparent_socket(addr: SocketAddr, source: IpAddr) -> io::Result<TcpSocket> {
+    let sock = new_socket(addr)?;
+    SockRef::from(&sock).set_ip_transparent(true)?;
+    sock.bind(SocketAddr::new(source, 0))?;
+    Ok(sock)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn transparent_socket(_: SocketAddr, _: IpAddr) -> io::Result<TcpSocket> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// One of our own addresses: only those can be bound without IP_TRANSPARENT
+pub fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback() || std::net::TcpListener::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+pub fn new_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    if addr.is_ipv6() {
+        TcpSocket::new_v6()
+    } else {
+        TcpSocket::new_v4()
+    }
+}
+
+/// Sends fake with a low ttl, then swaps the bytes under the kernel:
+/// the segment dies before the server and the retransmission carries real.
+/// fake and real have the same length.
+#[cfg(target_os = "linux")]
+pub async fn send_fake(s: &TcpStream, fake: &[u8], real: &[u8], fake_ttl: u32, ttl: u32) -> io::Result<()> {
+    use std::{fs::File, os::fd::{AsRawFd, FromRawFd}, os::unix::fs::FileExt};
+
+    let fd = unsafe { libc::memfd_create(c"fdpi".as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.write_all_at(fake, 0)?;
+
+    set_ttl(s, fake_ttl)?;
+    let mut offset: libc::off_t = 0;
+    while (offset as usize) < fake.len() {
+        s.writable().await?;
+        let r = s.try_io(Interest::WRITABLE, || {
+            let n = unsafe {
+                libc::sendfile(s.as_raw_fd(), file.as_raw_fd(), &mut offset, fake.len() - offset as usize)
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        match r {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            r => r?,
+        }
+    }
+    tokio::time::sleep(crate::fake::FAKE_WAIT).await;
+    file.write_all_at(real, 0)?;
+    set_ttl(s, ttl)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_fake(_: &TcpStream, _: &[u8], _: &[u8], _: u32, _: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// One byte with MSG_OOB
+pub async fn send_oob(s: &TcpStream, byte: u8) -> io::Result<()> {
+    loop {
+        s.writable().await?;
+        match s.try_io(Interest::WRITABLE, || SockRef::from(s).send_out_of_band(&[byte])) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            r => return r.map(|_| ()),
+        }
+    }
+}
+
+/// Segments sent and not acked yet (TCP_INFO tcpi_unacked)
+#[cfg(target_os = "linux")]
+pub fn unacked(s: &TcpStream) -> io::Result<u32> {
+    use std::os::fd::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            s.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(info.tcpi_unacked)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn unacked(_: &TcpStream) -> io::Result<u32> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,273 @@
+use crate::Result;
+use bytes::BytesMut;
+use std::time::Duration;
+use take_sni::take_sni_point;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const RECORD_HEADER: usize = 5;
+pub const HANDSHAKE: u8 = 0x16;
+/// max TLS plaintext record payload
+pub const MAX_RECORD: usize = 16384;
+/// max ClientHello we wait for
+const HELLO_CAP: usize = 64 * 1024;
+
+enum Hello {
+    Partial,
+    Complete,
+    NotTls,
+}
+
+fn scan(buf: &[u8]) -> Hello {
+    if buf.first().is_some_and(|b| *b != HANDSHAKE) {
+        return Hello::NotTls;
+    }
+    let mut pos = 0;
+    let mut head: Vec<u8> = Vec::with_capacity(4);
+    let mut payload_len = 0;
+    loop {
+        let Some(h) = buf.get(pos..pos + RECORD_HEADER) else {
+            return Hello::Partial;
+        };
+        if h[0] != HANDSHAKE {
+            return Hello::Complete;
+        }
+        let end = pos + RECORD_HEADER + u16::from_be_bytes([h[3], h[4]]) as usize;
+        if buf.len() < end {
+            return Hello::Partial;
+        }
+        let payload = &buf[pos + RECORD_HEADER..end];
+        head.extend(payload.iter().take(4 - head.len()));
+        payload_len += payload.len();
+        pos = end;
+        // handshake header: type(1) length(3)
+        if head.len() == 4 && payload_len >= 4 + u32::from_be_bytes([0, head[1], head[2], head[3]]) as usize {
+            return Hello::Complete;
+        }
+    }
+}
+
+/// Reads the complete ClientHello (possibly several TLS records) into buf.
+/// Stops on non-TLS data, EOF, the size cap or timeout and keeps what was read.
+pub async fn read_client_hello<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    timeout: Duration,
+) -> Result<()> {
+    match tokio::time::timeout(timeout, read_hello(reader, buf)).await {
+        Ok(r) => r,
+        Err(_) => {
+            log::debug!("[hello] timeout, {} bytes", buf.len());
+            Ok(())
+        }
+    }
+}
+
+async fn read_hello<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut BytesMut) -> Result<()> {
+    loop {
+        match scan(buf) {
+            Hello::Complete | Hello::NotTls => return Ok(()),
+            _ if buf.len() >= HELLO_CAP => {
+                log::debug!("[hello] cap {} bytes", HELLO_CAP);
+                return Ok(());
+            }
+            _ => {}
+        }
+        if reader.read_buf(buf).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Several handshake records -> one record, so the hello is parsed and split as a whole.
+pub fn join_records(buf: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(buf.len());
+    let mut pos = 0;
+    let mut records = 0;
+    while let Some(h) = buf.get(pos..pos + RECORD_HEADER) {
+        let end = pos + RECORD_HEADER + u16::from_be_bytes([h[3], h[4]]) as usize;
+        if h[0] != HANDSHAKE || end > buf.len() {
+            break;
+        }
+        payload.extend_from_slice(&buf[pos + RECORD_HEADER..end]);
+        pos = end;
+        records += 1;
+    }
+    if records < 2 || payload.len() > MAX_RECORD {
+        return buf.to_vec();
+    }
+    let mut r = Vec::with_capacity(buf.len());
+    r.extend_from_slice(&buf[..3]);
+    r.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    r.extend_from_slice(&payload);
+    r.extend_from_slice(&buf[pos..]);
+    r
+}
+
+/// server name from the ClientHello
+pub fn sni(hello: &[u8]) -> Option<String> {
+    let (p1, p2) = take_sni_point(hello)?;
+    Some(String::from_utf8_lossy(&hello[p1..p2]).to_ascii_lowercase())
+}
+
+/// The hello with the server name replaced, SNI extension, server_name list,
+/// extensions, handshake and record lengths fixed. One record hello only.
+pub fn replace_sni(hello: &[u8], name: &[u8]) -> Option<Vec<u8>> {
+    let (p1, p2) = take_sni_point(hello)?;
+    let ext = extensions_pos(hello)?;
+    let record = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+    let handshake = u32::from_be_bytes([0, hello[6], hello[7], hello[8]]) as usize;
+    if p1 < 9 || u16::from_be_bytes([hello[p1 - 2], hello[p1 - 1]]) as usize != p2 - p1 || record != handshake + 4 {
+        return None;
+    }
+    let delta = name.len() as isize - (p2 - p1) as isize;
+    let mut r = Vec::with_capacity(hello.len() + name.len());
+    r.extend_from_slice(&hello[..p1]);
+    r.extend_from_slice(name);
+    r.extend_from_slice(&hello[p2..]);
+    // name, server_name list, extension, extensions block
+    for pos in [p1 - 2, p1 - 5, p1 - 7, ext] {
+        add_len(&mut r[pos..pos + 2], delta)?;
+    }
+    add_len(&mut r[6..9], delta)?;
+    add_len(&mut r[3..5], delta)?;
+    Some(r)
+}
+
+/// position of the extensions length in a one record hello
+fn extensions_pos(hello: &[u8]) -> Option<usize> {
+    // record header, handshake header, version, random
+    let mut pos = RECORD_HEADER + 4 + 2 + 32;
+    pos += 1 + *hello.get(pos)? as usize;
+    pos += 2 + u16::from_be_bytes([*hello.get(pos)?, *hello.get(pos + 1)?]) as usize;
+    pos += 1 + *hello.get(pos)? as usize;
+    (pos + 2 <= hello.len()).then_some(pos)
+}
+
+/// big-endian length field += delta
+fn add_len(field: &mut [u8], delta: isize) -> Option<()> {
+    let v = field.iter().fold(0usize, |a, b| a << 8 | *b as usize);
+    let v = v.checked_add_signed(delta)?;
+    if v >> (8 * field.len()) != 0 {
+        return None;
+    }
+    for (i, b) in field.iter_mut().rev().enumerate() {
+        *b = (v >> (8 * i)) as u8;
+    }
+    Some(())
+}
+
+/// Splits the handshake record holding pos in two, the new header goes at pos.
+/// false - pos is not inside a record payload
+pub fn split_record(buf: &mut Vec<u8>, pos: usize) -> bool {
+    let mut start = 0;
+    while let Some(h) = buf.get(start..start + RECORD_HEADER) {
+        let end = start + RECORD_HEADER + u16::from_be_bytes([h[3], h[4]]) as usize;
+        if h[0] != HANDSHAKE || end > buf.len() {
+            return false;
+        }
+        if pos > start + RECORD_HEADER && pos < end {
+            let mut head = [h[0], h[1], h[2], 0, 0];
+            head[3..].copy_from_slice(&((end - pos) as u16).to_be_bytes());
+            buf[start + 3..start + 5].copy_from_slice(&((pos - start - RECORD_HEADER) as u16).to_be_bytes());
+            buf.splice(pos..pos, head);
+            return true;
+        }
+        start = end;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// one record ClientHello: sni, then supported_versions
+    fn hello(name: &[u8]) -> Vec<u8> {
+        let mut sni = vec![0, 0];
+        sni.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+        sni.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+        let ext = [sni, vec![0, 0x2b, 0, 3, 2, 3, 4]].concat();
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 1, 1, 0]);
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+
+        let mut r = vec![HANDSHAKE, 3, 1];
+        r.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+        r.extend_from_slice(&[1, 0]);
+        r.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        r.extend_from_slice(&body);
+        r
+    }
+
+    fn len_at(buf: &[u8], pos: usize) -> usize {
+        u16::from_be_bytes([buf[pos], buf[pos + 1]]) as usize
+    }
+
+    #[test]
+    fn replace_sni_lengths() {
+        let old = hello(b"blocked.example");
+        let new = replace_sni(&old, b"www.google.com").unwrap();
+        assert_eq!(new, hello(b"www.google.com"));
+
+        let (p1, p2) = take_sni_point(&new).unwrap();
+        assert_eq!(&new[p1..p2], b"www.google.com");
+        // name, server_name list, extension
+        assert_eq!(len_at(&new, p1 - 2), len_at(&old, p1 - 2) - 1);
+        assert_eq!(len_at(&new, p1 - 5), len_at(&old, p1 - 5) - 1);
+        assert_eq!(len_at(&new, p1 - 7), len_at(&old, p1 - 7) - 1);
+        // extensions, handshake, record
+        let ext = extensions_pos(&new).unwrap();
+        assert_eq!(len_at(&new, ext), len_at(&old, ext) - 1);
+        assert_eq!(len_at(&new, 7), len_at(&old, 7) - 1);
+        assert_eq!(len_at(&new, 3), new.len() - RECORD_HEADER);
+    }
+
+    #[test]
+    fn replace_sni_dot() {
+        let old = hello(b"example.com");
+        let new = replace_sni(&old, b"example.com.").unwrap();
+        assert_eq!(new, hello(b"example.com."));
+
+        let (p1, _) = take_sni_point(&new).unwrap();
+        for pos in [p1 - 2, p1 - 5, p1 - 7, extensions_pos(&new).unwrap(), 7, 3] {
+            assert_eq!(len_at(&new, pos), len_at(&old, pos) + 1, "length at {}", pos);
+        }
+    }
+
+    #[test]
+    fn replace_sni_rejects_bad_lengths() {
+        let mut h = hello(b"example.com");
+        h[4] += 1;
+        assert_eq!(replace_sni(&h, b"www.google.com"), None);
+    }
+
+    #[test]
+    fn split_record_at_sni() {
+        let old = hello(b"example.com");
+        let pos = take_sni_point(&old).unwrap().0 + 1;
+        let mut buf = old.clone();
+        assert!(split_record(&mut buf, pos));
+
+        assert_eq!(buf.len(), old.len() + RECORD_HEADER);
+        assert_eq!(len_at(&buf, 3), pos - RECORD_HEADER);
+        assert_eq!(&buf[pos..pos + 3], &[HANDSHAKE, 3, 1]);
+        assert_eq!(len_at(&buf, pos + 3), old.len() - pos);
+        assert_eq!(&buf[pos + RECORD_HEADER..], &old[pos..]);
+        assert_eq!(join_records(&buf), old);
+    }
+
+    #[test]
+    fn split_record_outside_payload() {
+        let mut buf = hello(b"example.com");
+        let len = buf.len();
+        assert!(!split_record(&mut buf, 2));
+        assert!(!split_record(&mut buf, len));
+        assert_eq!(buf, hello(b"example.com"));
+    }
+}